@@ -34,21 +34,38 @@ impl AABB {
 
         diff.as_ref()
             .iter()
-            .position(|&e| e == diff.min_element())
+            .position(|&e| e == diff.max_element())
             .unwrap()
     }
 
     /// Perform an intersection test with an AABB
+    ///
+    /// Only overlaps between the slabs and the [position_min, position_max]
+    /// interval of the ray count as hits.
+    ///
     /// Reference: https://medium.com/@bromanz/another-view-on-the-classic-ray
     /// -aabb-intersection-algorithm-for-bvh-traversal-41125138b525
-    pub fn hit(&self, ray: &Ray, _position_min: f32, _position_max: f32) -> bool {
+    pub fn hit(&self, ray: &Ray, position_min: f32, position_max: f32) -> bool {
+        self.entry(ray, position_min, position_max).is_some()
+    }
+
+    /// Find the parameter at which the ray enters the AABB
+    ///
+    /// The entry is clamped to position_min so rays starting inside the box
+    /// enter immediately. Returns None when the ray misses the box within
+    /// the [position_min, position_max] interval.
+    pub fn entry(&self, ray: &Ray, position_min: f32, position_max: f32) -> Option<f32> {
         let t0 = (self.minimum - ray.origin) * ray.inverse_direction;
         let t1 = (self.maximum - ray.origin) * ray.inverse_direction;
 
-        let tmin = t0.min(t1);
-        let tmax = t1.max(t0);
+        let tmin = t0.min(t1).max_element().max(position_min);
+        let tmax = t1.max(t0).min_element().min(position_max);
 
-        tmin.max_element() <= tmax.min_element()
+        if tmin <= tmax {
+            Some(tmin)
+        } else {
+            None
+        }
     }
 
     /// Create an AABB that encapsulates two volumes
@@ -1,118 +1,399 @@
 use std::cmp::Ordering;
 use std::sync::Arc;
 
+use glam::Vec3;
+
 use aabb::AABB;
 use hitable::{HitRecord, Hitable};
 use ray::Ray;
 
+/// The number of bins candidate splits are evaluated at along each axis
+const SAH_BINS: usize = 12;
+
+/// The maximum number of objects in a leaf used by BVH::new
+const DEFAULT_LEAF_SIZE: usize = 4;
+
+/// The cost of traversing a node relative to intersecting an object
+const TRAVERSAL_COST: f32 = 0.125;
+
+/// The maximum depth of the tree, bounding the traversal stack
+///
+/// Below half this depth nodes are split at the median so that the
+/// remaining subtree is balanced regardless of the heuristic.
+const MAX_DEPTH: usize = 64;
+
+/// A node of the flattened tree
+///
+/// Interior nodes store the index of their second child; the first child
+/// immediately follows the node in the array. Leaves store the offset of
+/// their first primitive and the number of primitives they hold.
 #[derive(Clone)]
-pub struct BVH {
-    left: Arc<dyn Hitable>,
-    right: Arc<dyn Hitable>,
+struct LinearNode {
+    bbox: AABB,
+    offset: u32,
+    count: u16,
+    axis: u8,
+}
+
+/// An object with its bounding box cached for construction
+struct Primitive {
+    object: Arc<dyn Hitable>,
     bbox: AABB,
+    centroid: Vec3,
+}
+
+#[derive(Clone)]
+pub struct BVH {
+    nodes: Vec<LinearNode>,
+    primitives: Vec<Arc<dyn Hitable>>,
 }
 
 impl BVH {
     /// Construct a new BVH from the objects in the scene.
     ///
-    /// An axis is chosen by random and the objects in the scene
-    /// are sorted upon that axis. Then, child objects are created
-    /// until only leaf nodes exist.
-    pub fn new(mut world: &mut Vec<Arc<dyn Hitable>>, start_time: f32, end_time: f32) -> BVH {
-        let mut main_box = world[0].bounding_box(start_time, end_time).unwrap();
-
-        for i in 1..world.len() {
-            let new_box = world[i].bounding_box(start_time, end_time).unwrap();
-            main_box = main_box.surrounding_box(&new_box);
+    /// Leaves hold up to four objects. See with_leaf_size.
+    pub fn new(world: &mut Vec<Arc<dyn Hitable>>, start_time: f32, end_time: f32) -> BVH {
+        BVH::with_leaf_size(world, start_time, end_time, DEFAULT_LEAF_SIZE)
+    }
+
+    /// Construct a new BVH with at most leaf_size objects in each leaf.
+    ///
+    /// Nodes are split with the surface area heuristic: the centroids of
+    /// the objects are binned along each axis and the split between bins
+    /// with the lowest expected cost of intersecting both children is
+    /// chosen. Nodes with few enough objects become leaves when no split
+    /// is cheaper than testing every object in the node.
+    ///
+    /// Reference:
+    /// Ingo Wald
+    /// On fast Construction of SAH-based Bounding Volume Hierarchies
+    /// IEEE Symposium on Interactive Ray Tracing 2007
+    pub fn with_leaf_size(world: &mut Vec<Arc<dyn Hitable>>,
+                          start_time: f32,
+                          end_time: f32,
+                          leaf_size: usize)
+                          -> BVH {
+        let mut primitives: Vec<Primitive> =
+            world.iter()
+                 .map(|object| {
+                     let bbox = object.bounding_box(start_time, end_time).unwrap();
+                     let centroid = 0.5 * (bbox.minimum + bbox.maximum);
+                     Primitive { object: object.clone(),
+                                 bbox,
+                                 centroid }
+                 })
+                 .collect();
+
+        let mut bvh = BVH { nodes: Vec::with_capacity(2 * primitives.len()),
+                            primitives: Vec::with_capacity(primitives.len()) };
+        if !primitives.is_empty() {
+            bvh.build(&mut primitives, leaf_size.clamp(1, u16::MAX as usize), 0);
+        }
+        bvh
+    }
+
+    /// Recompute the bounding boxes of the nodes for a new time interval
+    ///
+    /// The tree keeps the structure it was built with, so only the boxes of
+    /// moving objects change. Refitting is much cheaper than rebuilding and
+    /// lets one BVH built over a whole animation be tightened to the shutter
+    /// interval of each frame.
+    pub fn refit(&mut self, start_time: f32, end_time: f32) {
+        // children are always stored after their parents
+        for index in (0..self.nodes.len()).rev() {
+            let node = &self.nodes[index];
+            let bbox = if node.count > 0 {
+                let start = node.offset as usize;
+                self.primitives[start..start + node.count as usize]
+                    .iter()
+                    .filter_map(|object| object.bounding_box(start_time, end_time))
+                    .fold(AABB::new(), |bbox, object| bbox.surrounding_box(&object))
+            } else {
+                let second = &self.nodes[node.offset as usize].bbox;
+                self.nodes[index + 1].bbox.surrounding_box(second)
+            };
+            self.nodes[index].bbox = bbox;
+        }
+    }
+
+    /// Recursively build the nodes over the given primitives
+    ///
+    /// Nodes are laid out depth first and the index of the node built is
+    /// returned.
+    fn build(&mut self, primitives: &mut [Primitive], leaf_size: usize, depth: usize) -> usize {
+        let mut bbox = AABB::new();
+        let mut centroid_bounds = AABB::new();
+        for primitive in primitives.iter() {
+            bbox = bbox.surrounding_box(&primitive.bbox);
+            centroid_bounds =
+                centroid_bounds.surrounding_box(&AABB::from(primitive.centroid, primitive.centroid));
+        }
+
+        let index = self.nodes.len();
+        let mut leaf = |primitives: &[Primitive], bbox: AABB| {
+            let offset = self.primitives.len() as u32;
+            self.primitives.extend(primitives.iter().map(|p| p.object.clone()));
+            self.nodes.push(LinearNode { bbox,
+                                         offset,
+                                         count: primitives.len() as u16,
+                                         axis: 0 });
+            index
+        };
+
+        let count = primitives.len();
+        if count <= 1 {
+            return leaf(primitives, bbox);
+        }
+
+        let heuristic = if depth < MAX_DEPTH / 2 {
+            find_split(primitives, &bbox, &centroid_bounds)
+        } else {
+            None
+        };
+
+        let (axis, split) = match heuristic {
+            Some((axis, bin, cost)) => {
+                if count <= leaf_size && count as f32 <= cost {
+                    return leaf(primitives, bbox);
+                }
+                (axis, partition(primitives, &centroid_bounds, axis, bin))
+            }
+            None => {
+                if count <= leaf_size {
+                    return leaf(primitives, bbox);
+                }
+                (0, 0)
+            }
+        };
+
+        // fall back to a median split when the centroids cannot be separated
+        let (axis, split) = if split == 0 || split == count {
+            let axis = bbox.longest_axis();
+            primitives.sort_by(|a, b| {
+                          a.centroid.as_ref()[axis].partial_cmp(&b.centroid.as_ref()[axis])
+                                                   .unwrap_or(Ordering::Equal)
+                      });
+            (axis, count / 2)
+        } else {
+            (axis, split)
+        };
+
+        self.nodes.push(LinearNode { bbox,
+                                     offset: 0,
+                                     count: 0,
+                                     axis: axis as u8 });
+        let (left, right) = primitives.split_at_mut(split);
+        self.build(left, leaf_size, depth + 1);
+        let second = self.build(right, leaf_size, depth + 1);
+        self.nodes[index].offset = second as u32;
+        index
+    }
+}
+
+/// Find the bin index of a centroid along an axis
+fn bin_index(centroid: Vec3, centroid_bounds: &AABB, axis: usize) -> usize {
+    let minimum = centroid_bounds.minimum.as_ref()[axis];
+    let extent = centroid_bounds.maximum.as_ref()[axis] - minimum;
+    let bin = ((centroid.as_ref()[axis] - minimum) / extent * SAH_BINS as f32) as usize;
+    bin.min(SAH_BINS - 1)
+}
+
+/// Find the axis and bin with the lowest surface area heuristic cost
+///
+/// The cost is relative to intersecting a single object. Returns None
+/// when all centroids coincide and no split can separate them.
+fn find_split(primitives: &[Primitive],
+              bbox: &AABB,
+              centroid_bounds: &AABB)
+              -> Option<(usize, usize, f32)> {
+    let area = bbox.surface_area();
+    let extent = centroid_bounds.maximum - centroid_bounds.minimum;
+    let mut best: Option<(usize, usize, f32)> = None;
+
+    for axis in 0..3 {
+        if extent.as_ref()[axis] <= 0.0 {
+            continue;
         }
 
-        let axis = main_box.longest_axis();
+        let mut counts = [0usize; SAH_BINS];
+        let mut bounds = vec![AABB::new(); SAH_BINS];
+        for primitive in primitives {
+            let bin = bin_index(primitive.centroid, centroid_bounds, axis);
+            counts[bin] += 1;
+            bounds[bin] = bounds[bin].surrounding_box(&primitive.bbox);
+        }
 
-        world.sort_by(|a, b| box_compare(a, b, axis, start_time, end_time));
+        // sweep from the right to find the area and count right of each split
+        let mut right_areas = [0.0f32; SAH_BINS];
+        let mut right_counts = [0usize; SAH_BINS];
+        let mut accumulated = AABB::new();
+        let mut accumulated_count = 0;
+        for bin in (1..SAH_BINS).rev() {
+            accumulated = accumulated.surrounding_box(&bounds[bin]);
+            accumulated_count += counts[bin];
+            right_areas[bin] = accumulated.surface_area();
+            right_counts[bin] = accumulated_count;
+        }
 
-        let mut left = world[0].clone();
-        let mut right = world[0].clone();
+        let mut left_box = AABB::new();
+        let mut left_count = 0;
+        for bin in 1..SAH_BINS {
+            left_box = left_box.surrounding_box(&bounds[bin - 1]);
+            left_count += counts[bin - 1];
 
-        if world.len() == 2 {
-            left = world[0].clone();
-            right = world[1].clone();
-        } else if world.len() > 2 {
-            let mut right_objects = world.split_off(world.len() / 2);
-            left = Arc::new(BVH::new(&mut world, start_time, end_time));
-            right = Arc::new(BVH::new(&mut right_objects, start_time, end_time));
+            if left_count == 0 || right_counts[bin] == 0 {
+                continue;
+            }
+
+            let cost = TRAVERSAL_COST
+                       + (left_box.surface_area() * left_count as f32
+                          + right_areas[bin] * right_counts[bin] as f32)
+                         / area;
+
+            if best.is_none_or(|(_, _, best_cost)| cost < best_cost) {
+                best = Some((axis, bin, cost));
+            }
         }
+    }
 
-        let bbox = left.bounding_box(start_time, end_time)
-                       .unwrap()
-                       .surrounding_box(&right.bounding_box(start_time, end_time).unwrap());
+    best
+}
 
-        BVH { left, right, bbox }
+/// Move the primitives left of the split bin to the front of the slice
+///
+/// Returns the number of primitives left of the split.
+fn partition(primitives: &mut [Primitive],
+             centroid_bounds: &AABB,
+             axis: usize,
+             bin: usize)
+             -> usize {
+    let mut split = 0;
+    for i in 0..primitives.len() {
+        if bin_index(primitives[i].centroid, centroid_bounds, axis) < bin {
+            primitives.swap(i, split);
+            split += 1;
+        }
     }
+    split
 }
 
 impl Hitable for BVH {
     /// Test whether the ray intersects the bounding volume.
     ///
-    /// We check for an intersection with a node in the BVH and
-    /// return the node that is hit. If both the left and right
-    /// child are hit, then we return the node closest to the ray.
+    /// The nodes are traversed front to back with an explicit stack: the
+    /// child on the near side of the split axis is visited first and the
+    /// far child is deferred. Every hit shrinks the interval of the ray so
+    /// deferred nodes whose boxes are entered beyond the closest hit are
+    /// skipped when they are popped.
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        if self.bbox.hit(&ray, t_min, t_max) {
-            let left = self.left.hit(&ray, t_min, t_max);
-            let right = self.right.hit(&ray, t_min, t_max);
-            match (left, right) {
-                (Some(left), Some(right)) => {
-                    if left.parameter < right.parameter {
-                        Some(left)
-                    } else {
-                        Some(right)
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let negative = [ray.direction.x() < 0.0,
+                        ray.direction.y() < 0.0,
+                        ray.direction.z() < 0.0];
+
+        let mut closest: Option<HitRecord> = None;
+        let mut t_closest = t_max;
+        let mut stack = [0usize; MAX_DEPTH];
+        let mut top = 0;
+        let mut current = 0;
+
+        loop {
+            let node = &self.nodes[current];
+            if node.bbox.hit(ray, t_min, t_closest) {
+                if node.count > 0 {
+                    let start = node.offset as usize;
+                    for object in &self.primitives[start..start + node.count as usize] {
+                        if let Some(hit) = object.hit(ray, t_min, t_closest) {
+                            t_closest = hit.parameter;
+                            closest = Some(hit);
+                        }
                     }
+                } else {
+                    let (near, far) = if negative[node.axis as usize] {
+                        (node.offset as usize, current + 1)
+                    } else {
+                        (current + 1, node.offset as usize)
+                    };
+                    stack[top] = far;
+                    top += 1;
+                    current = near;
+                    continue;
                 }
-                (Some(left), None) => Some(left),
-                (None, Some(right)) => Some(right),
-                _ => None,
             }
-        } else {
-            None
+
+            if top == 0 {
+                break;
+            }
+            top -= 1;
+            current = stack[top];
         }
+
+        closest
     }
 
     fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
-        Some(self.bbox.clone())
+        self.nodes.first().map(|node| node.bbox.clone())
     }
 }
 
-/// Compare the coordinates of two bounding volumes.
-///
-/// We compare two bounding volumes based on their minimum
-/// slab and return the order with the volume with the
-/// least minimum first.
-fn box_compare(a: &Arc<dyn Hitable>,
-               b: &Arc<dyn Hitable>,
-               axis: usize,
-               start_time: f32,
-               end_time: f32)
-               -> Ordering {
-    if axis == 0 {
-        a.bounding_box(start_time, end_time)
-         .unwrap()
-         .minimum
-         .x()
-         .partial_cmp(&b.bounding_box(start_time, end_time).unwrap().minimum.x())
-         .unwrap()
-    } else if axis == 1 {
-        a.bounding_box(start_time, end_time)
-         .unwrap()
-         .minimum
-         .y()
-         .partial_cmp(&b.bounding_box(start_time, end_time).unwrap().minimum.y())
-         .unwrap()
-    } else {
-        a.bounding_box(start_time, end_time)
-         .unwrap()
-         .minimum
-         .z()
-         .partial_cmp(&b.bounding_box(start_time, end_time).unwrap().minimum.z())
-         .unwrap()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use materials::Empty;
+    use sphere::Sphere;
+    use world::World;
+
+    #[test]
+    fn test_bvh_matches_linear_search() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut world = World::new();
+        for _ in 0..200 {
+            let center = Vec3::new(rng.gen_range(-10.0, 10.0),
+                                   rng.gen_range(-10.0, 10.0),
+                                   rng.gen_range(-10.0, 10.0));
+            world.add(Sphere::new(center, center, rng.gen_range(0.1, 1.0), Empty::new(), 0.0, 1.0));
+        }
+        let bvh = BVH::with_leaf_size(&mut world.objects.clone(), 0.0, 1.0, 2);
+
+        for _ in 0..500 {
+            let origin = Vec3::new(rng.gen_range(-15.0, 15.0), rng.gen_range(-15.0, 15.0), 20.0);
+            let target = Vec3::new(rng.gen_range(-10.0, 10.0), rng.gen_range(-10.0, 10.0), 0.0);
+            let ray = Ray::new(origin, target - origin, 0.0);
+
+            let expected = world.hit(&ray, 1e-4, f32::MAX).map(|hit| hit.parameter);
+            let found = bvh.hit(&ray, 1e-4, f32::MAX).map(|hit| hit.parameter);
+            assert_eq!(expected, found);
+        }
+    }
+
+    #[test]
+    fn test_refit_tightens_moving_objects() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut world = World::new();
+        for _ in 0..100 {
+            let start = Vec3::new(rng.gen_range(-10.0, 10.0), rng.gen_range(-10.0, 10.0), 0.0);
+            let end = start + Vec3::new(rng.gen_range(-5.0, 5.0), rng.gen_range(-5.0, 5.0), 0.0);
+            world.add(Sphere::new(start, end, 0.5, Empty::new(), 0.0, 1.0));
+        }
+        let mut bvh = BVH::new(&mut world.objects.clone(), 0.0, 1.0);
+        let full = bvh.bounding_box(0.0, 1.0).unwrap().surface_area();
+        bvh.refit(0.75, 0.75);
+        assert!(bvh.bounding_box(0.0, 1.0).unwrap().surface_area() < full);
+
+        for _ in 0..500 {
+            let origin = Vec3::new(rng.gen_range(-15.0, 15.0), rng.gen_range(-15.0, 15.0), 20.0);
+            let target = Vec3::new(rng.gen_range(-10.0, 10.0), rng.gen_range(-10.0, 10.0), 0.0);
+            let ray = Ray::new(origin, target - origin, 0.75);
+
+            let expected = world.hit(&ray, 1e-4, f32::MAX).map(|hit| hit.parameter);
+            let found = bvh.hit(&ray, 1e-4, f32::MAX).map(|hit| hit.parameter);
+            assert_eq!(expected, found);
+        }
     }
 }
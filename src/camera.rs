@@ -1,57 +1,209 @@
+use std::f32;
 use std::f32::consts::PI;
+use std::sync::Arc;
 
-use glam::Vec3;
+use glam::{Mat3, Vec2, Vec3};
 use rand::rngs::ThreadRng;
 use rand::Rng;
 
-use integrator::pick_sphere_point;
+use hitable::Hitable;
+use lens::Lens;
 use ray::Ray;
 
-pub struct Camera {
-    pub lower_left_corner: Vec3,
-    pub horizontal: Vec3,
-    pub vertical: Vec3,
+/// The position and target of the camera at a point in time
+#[derive(Clone, Copy)]
+pub struct CameraKeyframe {
+    pub time: f32,
     pub origin: Vec3,
-    u: Vec3,
-    v: Vec3,
-    w: Vec3,
-    pub lens_radius: f32,
+    pub lookat: Vec3,
+}
+
+impl CameraKeyframe {
+    pub fn new(time: f32, origin: Vec3, lookat: Vec3) -> CameraKeyframe {
+        CameraKeyframe { time, origin, lookat }
+    }
+}
+
+/// Placement positions a camera in the world and holds its shutter interval
+///
+/// The camera looks from origin toward lookat and the view vector
+/// determines the tilt of the camera. u, v and w form an orthonormal basis
+/// with u pointing right, v pointing up and w pointing backward, away from
+/// the lookat point.
+#[derive(Clone)]
+pub struct Placement {
+    pub origin: Vec3,
+    pub lookat: Vec3,
+    pub view: Vec3,
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3,
     pub start_time: f32,
     pub end_time: f32,
     pub atmosphere: bool,
+    keyframes: Vec<CameraKeyframe>,
 }
 
-impl Camera {
-    /// Create a new camera with which to see the world!
-    ///
-    /// The origin determines where the eye is placed on the camera.
-    /// The lookat variable determines where in the world the eye is looking.
-    /// The view vector is responsible for determining the tilt of the camera.
-    /// FOV is the angle at which the eye is looking through the camera.
-    /// The aspect ratio is the proportial difference between the width and height.
-    /// aperture controls how big the lens of the camera is and focus distance
-    /// controls the shortest distance that the camera can focus.
+impl Placement {
     pub fn new(origin: Vec3,
                lookat: Vec3,
                view: Vec3,
-               fov: f32,
-               aspect: f32,
-               aperture: f32,
-               focus_distance: f32,
                start_time: f32,
                end_time: f32,
                atmosphere: bool)
-               -> Camera {
+               -> Placement {
+        let w: Vec3 = (origin - lookat).normalize();
+        let u: Vec3 = view.cross(w).normalize();
+        let v: Vec3 = w.cross(u);
+
+        Placement { origin,
+                    lookat,
+                    view,
+                    u,
+                    v,
+                    w,
+                    start_time,
+                    end_time,
+                    atmosphere,
+                    keyframes: Vec::new() }
+    }
+
+    /// Move the camera through keyframes of its origin and lookat
+    ///
+    /// The keyframes only take effect in the placements returned by frame.
+    pub fn with_keyframes(mut self, mut keyframes: Vec<CameraKeyframe>) -> Placement {
+        keyframes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+        self.keyframes = keyframes;
+        self
+    }
+
+    /// Find the placement of a frame whose shutter is open from start_time to end_time
+    ///
+    /// The keyframes are interpolated linearly at the middle of the shutter
+    /// interval. A placement without keyframes keeps its position and only
+    /// takes the new shutter interval.
+    pub fn frame(&self, start_time: f32, end_time: f32) -> Placement {
+        let time = 0.5 * (start_time + end_time);
+        let next = self.keyframes.partition_point(|keyframe| keyframe.time <= time);
+
+        let (origin, lookat) = if self.keyframes.is_empty() {
+            (self.origin, self.lookat)
+        } else if next == 0 {
+            (self.keyframes[0].origin, self.keyframes[0].lookat)
+        } else if next == self.keyframes.len() {
+            (self.keyframes[next - 1].origin, self.keyframes[next - 1].lookat)
+        } else {
+            let (a, b) = (&self.keyframes[next - 1], &self.keyframes[next]);
+            let t = (time - a.time) / (b.time - a.time);
+            (a.origin + t * (b.origin - a.origin), a.lookat + t * (b.lookat - a.lookat))
+        };
+
+        Placement::new(origin, lookat, self.view, start_time, end_time, self.atmosphere)
+            .with_keyframes(self.keyframes.clone())
+    }
+
+    /// Sample a time while the shutter is open
+    pub fn sample_time(&self, rng: &mut ThreadRng) -> f32 {
+        self.start_time + rng.gen::<f32>() * (self.end_time - self.start_time)
+    }
+
+    /// Transform a direction from camera space, where -Z looks forward, to world space
+    pub fn to_world(&self, direction: Vec3) -> Vec3 {
+        direction.x() * self.u + direction.y() * self.v + direction.z() * self.w
+    }
+}
+
+/// A ray leaving the camera and the weight of each color channel it carries
+pub struct CameraSample {
+    pub ray: Ray,
+    pub weight: Vec3,
+}
+
+impl CameraSample {
+    pub fn new(ray: Ray) -> CameraSample {
+        CameraSample { ray,
+                       weight: Vec3::one() }
+    }
+}
+
+/// The Camera trait is implemented by every projection from the image to the world
+pub trait Camera: Send + Sync {
+    /// Generate the ray through the point (s, t) of the image
+    ///
+    /// s runs from the left to the right edge of the image and t from the
+    /// bottom to the top. Points the projection does not cover return None.
+    fn generate_ray(&self, s: f32, t: f32, rng: &mut ThreadRng) -> Option<CameraSample>;
+
+    fn placement(&self) -> &Placement;
+
+    /// Create the same camera at another placement
+    fn placed(&self, placement: Placement) -> Box<dyn Camera>;
+
+    /// Find the camera of a frame whose shutter is open from start_time to end_time
+    fn frame(&self, start_time: f32, end_time: f32) -> Box<dyn Camera> {
+        self.placed(self.placement().frame(start_time, end_time))
+    }
+
+    /// The factor converting scene radiance to the recorded pixel values
+    fn exposure(&self) -> f32 {
+        1.0
+    }
+
+    /// The angle in radians between the rays through neighboring pixels of an image
+    ///
+    /// Rays are traced as cones this wide to filter textures. Cameras whose
+    /// rays don't spread apart, like orthographic ones, see the finest detail.
+    fn spread_angle(&self, _width: usize, _height: usize) -> f32 {
+        0.0
+    }
+}
+
+/// What an autofocusing camera brings into focus
+#[derive(Clone)]
+pub enum Focus {
+    /// The first surface seen through the point (s, t) of the image
+    Pixel(f32, f32),
+    /// A point in the world
+    Point(Vec3),
+    /// The side of an object facing the camera, even when other objects hide it
+    Object(Arc<dyn Hitable>),
+}
+
+/// Perspective is a thin lens camera
+///
+/// FOV is the vertical angle in degrees that the camera sees. The aspect
+/// ratio is the proportional difference between the width and height.
+/// aperture controls how big the lens of the camera is and focus distance
+/// controls the distance at which the camera is in focus. The shape of the
+/// aperture and other effects of the lens are set with with_lens.
+#[derive(Clone)]
+pub struct Perspective {
+    placement: Placement,
+    fov: f32,
+    aspect: f32,
+    focus_distance: f32,
+    lower_left_corner: Vec3,
+    horizontal: Vec3,
+    vertical: Vec3,
+    lens_radius: f32,
+    lens: Lens,
+    focus_normal: Vec3,
+}
+
+impl Perspective {
+    pub fn new(placement: Placement,
+               fov: f32,
+               aspect: f32,
+               aperture: f32,
+               focus_distance: f32)
+               -> Perspective {
         let lens_radius: f32 = aperture / 2.0;
         let theta: f32 = fov * PI / 180.0;
         let half_height: f32 = (theta / 2.0).tan();
         let half_width: f32 = aspect * half_height;
 
-        let w: Vec3 = (origin - lookat).normalize();
-        let u: Vec3 = view.cross(w).normalize();
-        let v: Vec3 = w.cross(u);
-
-        let lower_left_corner: Vec3 = origin
+        let (u, v, w) = (placement.u, placement.v, placement.w);
+        let lower_left_corner: Vec3 = placement.origin
                                       - half_width * focus_distance * u
                                       - half_height * focus_distance * v
                                       - focus_distance * w;
@@ -59,28 +211,838 @@ impl Camera {
         let horizontal: Vec3 = 2.0 * half_width * focus_distance * u;
         let vertical: Vec3 = 2.0 * half_height * focus_distance * v;
 
-        Camera { lower_left_corner,
-                 horizontal,
-                 vertical,
-                 origin,
-                 u,
-                 v,
-                 w,
-                 lens_radius,
-                 start_time,
-                 end_time,
-                 atmosphere }
-    }
-
-    /// Get the ray that is coming from the camera into the world
-    pub fn get_ray(&self, s: f32, t: f32, mut rng: &mut ThreadRng) -> Ray {
-        let radius: Vec3 = self.lens_radius * pick_sphere_point(&mut rng);
-        let offset: Vec3 = self.u * radius.x() + self.v * radius.y();
-        let time = self.start_time + rng.gen::<f32>() * (self.end_time - self.start_time);
-        Ray::new(self.origin + offset,
-                 self.lower_left_corner + s * self.horizontal + t * self.vertical
-                 - self.origin
-                 - offset,
-                 time)
+        Perspective { focus_normal: -placement.w,
+                      placement,
+                      fov,
+                      aspect,
+                      focus_distance,
+                      lower_left_corner,
+                      horizontal,
+                      vertical,
+                      lens_radius,
+                      lens: Lens::new() }
+    }
+
+    pub fn with_lens(mut self, lens: Lens) -> Perspective {
+        let tilt = Mat3::from_axis_angle(self.placement.v, lens.tilt.y().to_radians())
+                   * Mat3::from_axis_angle(self.placement.u, lens.tilt.x().to_radians());
+        self.focus_normal = tilt * -self.placement.w;
+        self.lens = lens;
+        self
+    }
+
+    /// Set the focus distance to the distance of a target in the world
+    ///
+    /// The distance is measured along the view direction, so the target
+    /// lies on the plane in focus. A target behind the camera, or a pixel
+    /// that sees nothing, keeps the current focus distance. The target is
+    /// found once at the start of the shutter and kept by later frames.
+    pub fn with_autofocus(self, world: &dyn Hitable, focus: &Focus) -> Perspective {
+        match self.distance_to(world, focus) {
+            Some(focus_distance) => Perspective::new(self.placement.clone(),
+                                                     self.fov,
+                                                     self.aspect,
+                                                     2.0 * self.lens_radius,
+                                                     focus_distance).with_lens(self.lens.clone()),
+            None => self,
+        }
+    }
+
+    /// Find the distance along the view direction to a focus target
+    fn distance_to(&self, world: &dyn Hitable, focus: &Focus) -> Option<f32> {
+        let origin = self.placement.origin;
+        let time = self.placement.start_time;
+        let point = match *focus {
+            Focus::Pixel(s, t) => {
+                let target = self.lower_left_corner + s * self.horizontal + t * self.vertical;
+                world.hit(&Ray::new(origin, target - origin, time), 1e-4, f32::MAX)?.point
+            }
+            Focus::Point(point) => point,
+            Focus::Object(ref object) => {
+                let bbox = object.bounding_box(time, time)?;
+                let center = 0.5 * (bbox.minimum + bbox.maximum);
+                object.hit(&Ray::new(origin, center - origin, time), 1e-4, f32::MAX)
+                      .map_or(center, |hit| hit.point)
+            }
+        };
+
+        let distance = (point - origin).dot(-self.placement.w);
+        if distance > 0.0 {
+            Some(distance)
+        } else {
+            None
+        }
+    }
+}
+
+impl Camera for Perspective {
+    /// Generate a ray through a point on the lens toward the plane in focus
+    ///
+    /// With chromatic aberration each ray carries a single color channel,
+    /// chosen at random and weighted to keep the image unbiased.
+    fn generate_ray(&self, s: f32, t: f32, rng: &mut ThreadRng) -> Option<CameraSample> {
+        let (mut s, mut t) = (s, t);
+        let mut weight = Vec3::one();
+        if self.lens.chromatic_aberration != Vec3::one() {
+            let channel = rng.gen_range(0, 3);
+            let scale = self.lens.chromatic_aberration.as_ref()[channel];
+            s = 0.5 + scale * (s - 0.5);
+            t = 0.5 + scale * (t - 0.5);
+            weight = Vec3::zero();
+            weight.as_mut()[channel] = 3.0;
+        }
+
+        let pinhole = self.placement.origin;
+        let mut target = self.lower_left_corner + s * self.horizontal + t * self.vertical;
+        if self.focus_normal != -self.placement.w {
+            let direction = target - pinhole;
+            let center = pinhole - self.focus_distance * self.placement.w;
+            let denominator = direction.dot(self.focus_normal);
+            if denominator > 0.0 {
+                let distance = (center - pinhole).dot(self.focus_normal) / denominator;
+                target = pinhole + distance * direction;
+            }
+        }
+
+        let point = self.lens.aperture.sample(rng);
+        if self.lens.cat_eye > 0.0 {
+            // the barrel of the lens is a second circle moving off the aperture toward the corners
+            let position = Vec2::new(2.0 * s - 1.0, 2.0 * t - 1.0) / 2.0f32.sqrt();
+            if (point - self.lens.cat_eye * position).length() > 1.0 {
+                return None;
+            }
+        }
+
+        let (u, v) = (self.placement.u, self.placement.v);
+        let origin = pinhole + self.lens_radius * (point.x() * u + point.y() * v);
+        Some(CameraSample { ray: Ray::new(origin, target - origin, self.placement.sample_time(rng)),
+                            weight })
+    }
+
+    fn placement(&self) -> &Placement {
+        &self.placement
+    }
+
+    fn placed(&self, placement: Placement) -> Box<dyn Camera> {
+        Box::new(Perspective::new(placement,
+                                  self.fov,
+                                  self.aspect,
+                                  2.0 * self.lens_radius,
+                                  self.focus_distance).with_lens(self.lens.clone()))
+    }
+
+    fn spread_angle(&self, _width: usize, height: usize) -> f32 {
+        2.0 * (0.5 * self.fov * PI / 180.0).tan() / height as f32
+    }
+}
+
+/// The dimensions of a camera sensor in millimeters
+#[derive(Clone, Copy)]
+pub struct Sensor {
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Sensor {
+    pub fn new(width: f32, height: f32) -> Sensor {
+        Sensor { width, height }
+    }
+
+    pub fn full_frame() -> Sensor {
+        Sensor::new(36.0, 24.0)
+    }
+
+    pub fn super_35() -> Sensor {
+        Sensor::new(24.89, 18.66)
+    }
+
+    pub fn aps_c() -> Sensor {
+        Sensor::new(23.6, 15.6)
+    }
+
+    pub fn micro_four_thirds() -> Sensor {
+        Sensor::new(17.3, 13.0)
+    }
+}
+
+/// Physical is a thin lens camera described by the settings of a real camera
+///
+/// The field of view follows from the focal length and the width of the
+/// sensor, which is fit to the width of the image. The aperture is the
+/// focal length divided by the f-number, with scene units taken to be
+/// meters, and the shutter stays open for shutter_speed seconds from the
+/// start time of the placement. Defaults to 1/125 s at ISO 100.
+///
+/// The shutter speed replaces the end time of any placement the camera is
+/// given. Frames of an animation are the exception: their shutter interval
+/// comes from the frame rate and shutter fraction of the sequence, and it
+/// becomes the shutter speed so that motion blur and exposure agree.
+#[derive(Clone)]
+pub struct Physical {
+    thin_lens: Perspective,
+    aspect: f32,
+    focal_length: f32,
+    sensor: Sensor,
+    f_number: f32,
+    focus_distance: f32,
+    shutter_speed: f32,
+    iso: f32,
+}
+
+impl Physical {
+    pub fn new(placement: Placement,
+               aspect: f32,
+               focal_length: f32,
+               sensor: Sensor,
+               f_number: f32,
+               focus_distance: f32)
+               -> Physical {
+        let thin_lens = Perspective::new(placement.clone(), 90.0, aspect, 0.0, focus_distance);
+        Physical { thin_lens,
+                   aspect,
+                   focal_length,
+                   sensor,
+                   f_number,
+                   focus_distance,
+                   shutter_speed: 1.0 / 125.0,
+                   iso: 100.0 }.with_placement(placement)
+    }
+
+    pub fn with_shutter_speed(mut self, shutter_speed: f32) -> Physical {
+        self.shutter_speed = shutter_speed;
+        let placement = self.thin_lens.placement.clone();
+        self.with_placement(placement)
+    }
+
+    pub fn with_iso(mut self, iso: f32) -> Physical {
+        self.iso = iso;
+        self
+    }
+
+    pub fn with_lens(mut self, lens: Lens) -> Physical {
+        self.thin_lens = self.thin_lens.with_lens(lens);
+        self
+    }
+
+    /// Set the focus distance to the distance of a target in the world
+    ///
+    /// See Perspective::with_autofocus.
+    pub fn with_autofocus(mut self, world: &dyn Hitable, focus: &Focus) -> Physical {
+        self.thin_lens = self.thin_lens.with_autofocus(world, focus);
+        self.focus_distance = self.thin_lens.focus_distance;
+        self
+    }
+
+    /// Build the thin lens matching the camera settings at the placement
+    fn with_placement(mut self, mut placement: Placement) -> Physical {
+        placement.end_time = placement.start_time + self.shutter_speed;
+
+        let half_width = 0.5 * self.sensor.width / self.focal_length;
+        let half_height = half_width / self.aspect;
+        let fov = 2.0 * half_height.atan() * 180.0 / PI;
+        let aperture = 0.001 * self.focal_length / self.f_number;
+
+        let lens = self.thin_lens.lens.clone();
+        let focus_distance = self.focus_distance;
+        self.thin_lens =
+            Perspective::new(placement, fov, self.aspect, aperture, focus_distance).with_lens(lens);
+        self
+    }
+}
+
+impl Camera for Physical {
+    fn generate_ray(&self, s: f32, t: f32, rng: &mut ThreadRng) -> Option<CameraSample> {
+        self.thin_lens.generate_ray(s, t, rng)
+    }
+
+    fn placement(&self) -> &Placement {
+        &self.thin_lens.placement
+    }
+
+    fn placed(&self, placement: Placement) -> Box<dyn Camera> {
+        Box::new(self.clone().with_placement(placement))
+    }
+
+    /// Keep the shutter open for the whole interval of the frame
+    fn frame(&self, start_time: f32, end_time: f32) -> Box<dyn Camera> {
+        let mut camera = self.clone();
+        camera.shutter_speed = end_time - start_time;
+        Box::new(camera.with_placement(self.placement().frame(start_time, end_time)))
+    }
+
+    fn spread_angle(&self, width: usize, height: usize) -> f32 {
+        self.thin_lens.spread_angle(width, height)
+    }
+
+    /// Find the exposure of a digital camera with saturation based sensitivity
+    ///
+    /// Scene luminance in candela per square meter that saturates the
+    /// sensor maps to one. This assumes radiance is in those units, which
+    /// the emission of most scenes is not, so lights seen through this
+    /// camera need photometric strengths to be exposed sensibly.
+    ///
+    /// Reference:
+    /// Sébastien Lagarde, Charles de Rousiers
+    /// Moving Frostbite to Physically Based Rendering 3.0
+    /// SIGGRAPH 2014 Course: Physically Based Shading in Theory and Practice
+    fn exposure(&self) -> f32 {
+        let ev100 = (self.f_number * self.f_number / self.shutter_speed * 100.0 / self.iso).log2();
+        1.0 / (1.2 * 2.0f32.powf(ev100))
+    }
+}
+
+/// Orthographic casts parallel rays from a rectangle facing the lookat point
+///
+/// The rectangle is width units wide and centered on the origin.
+#[derive(Clone)]
+pub struct Orthographic {
+    placement: Placement,
+    width: f32,
+    aspect: f32,
+}
+
+impl Orthographic {
+    pub fn new(placement: Placement, width: f32, aspect: f32) -> Orthographic {
+        Orthographic { placement,
+                       width,
+                       aspect }
+    }
+}
+
+impl Camera for Orthographic {
+    fn generate_ray(&self, s: f32, t: f32, rng: &mut ThreadRng) -> Option<CameraSample> {
+        let height = self.width / self.aspect;
+        let origin = self.placement.origin
+                     + (s - 0.5) * self.width * self.placement.u
+                     + (t - 0.5) * height * self.placement.v;
+        let time = self.placement.sample_time(rng);
+        Some(CameraSample::new(Ray::new(origin, -self.placement.w, time)))
+    }
+
+    fn placement(&self) -> &Placement {
+        &self.placement
+    }
+
+    fn placed(&self, placement: Placement) -> Box<dyn Camera> {
+        Box::new(Orthographic::new(placement, self.width, self.aspect))
+    }
+}
+
+/// How a fisheye lens maps the angle from its axis to the distance from the image center
+#[derive(Clone, Copy)]
+pub enum FisheyeMapping {
+    /// The distance grows linearly with the angle
+    Equidistant,
+    /// Equal solid angles cover equal areas of the image
+    Equisolid,
+}
+
+/// Fisheye projects a circle inscribed in the shorter side of the image
+///
+/// FOV is the full angle in degrees across the image circle and may exceed
+/// 180 degrees. Points outside the image circle produce no rays.
+#[derive(Clone)]
+pub struct Fisheye {
+    placement: Placement,
+    fov: f32,
+    aspect: f32,
+    mapping: FisheyeMapping,
+}
+
+impl Fisheye {
+    pub fn new(placement: Placement, fov: f32, aspect: f32, mapping: FisheyeMapping) -> Fisheye {
+        Fisheye { placement,
+                  fov,
+                  aspect,
+                  mapping }
+    }
+}
+
+impl Camera for Fisheye {
+    fn generate_ray(&self, s: f32, t: f32, rng: &mut ThreadRng) -> Option<CameraSample> {
+        let (mut x, mut y) = (2.0 * s - 1.0, 2.0 * t - 1.0);
+        if self.aspect > 1.0 {
+            x *= self.aspect;
+        } else {
+            y /= self.aspect;
+        }
+
+        let radius = (x * x + y * y).sqrt();
+        if radius > 1.0 {
+            return None;
+        }
+
+        let half_fov = 0.5 * self.fov * PI / 180.0;
+        let theta = match self.mapping {
+            FisheyeMapping::Equidistant => radius * half_fov,
+            FisheyeMapping::Equisolid => 2.0 * (radius * (0.5 * half_fov).sin()).asin(),
+        };
+        let phi = y.atan2(x);
+
+        let direction = Vec3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), -theta.cos());
+        Some(CameraSample::new(Ray::new(self.placement.origin,
+                                        self.placement.to_world(direction),
+                                        self.placement.sample_time(rng))))
+    }
+
+    fn placement(&self) -> &Placement {
+        &self.placement
+    }
+
+    fn placed(&self, placement: Placement) -> Box<dyn Camera> {
+        Box::new(Fisheye::new(placement, self.fov, self.aspect, self.mapping))
+    }
+
+    fn spread_angle(&self, width: usize, height: usize) -> f32 {
+        self.fov * PI / 180.0 / width.min(height) as f32
+    }
+}
+
+/// Equirectangular sees every direction around the camera
+///
+/// Longitude runs across the image, with the lookat point in the center,
+/// and latitude runs up the image. Images with a 2:1 aspect ratio give
+/// square pixels, the usual layout of environment maps.
+#[derive(Clone)]
+pub struct Equirectangular {
+    placement: Placement,
+    exposure: f32,
+}
+
+impl Equirectangular {
+    pub fn new(placement: Placement) -> Equirectangular {
+        Equirectangular { placement,
+                          exposure: 1.0 }
+    }
+
+    /// Expose the panorama like the camera it stands in for
+    pub fn with_exposure(mut self, exposure: f32) -> Equirectangular {
+        self.exposure = exposure;
+        self
+    }
+}
+
+impl Camera for Equirectangular {
+    fn generate_ray(&self, s: f32, t: f32, rng: &mut ThreadRng) -> Option<CameraSample> {
+        let longitude = 2.0 * PI * (s - 0.5);
+        let latitude = PI * (t - 0.5);
+
+        let direction = Vec3::new(latitude.cos() * longitude.sin(),
+                                  latitude.sin(),
+                                  -latitude.cos() * longitude.cos());
+        Some(CameraSample::new(Ray::new(self.placement.origin,
+                                        self.placement.to_world(direction),
+                                        self.placement.sample_time(rng))))
+    }
+
+    fn placement(&self) -> &Placement {
+        &self.placement
+    }
+
+    fn placed(&self, placement: Placement) -> Box<dyn Camera> {
+        Box::new(Equirectangular::new(placement).with_exposure(self.exposure))
+    }
+
+    fn exposure(&self) -> f32 {
+        self.exposure
+    }
+
+    fn spread_angle(&self, _width: usize, height: usize) -> f32 {
+        PI / height as f32
+    }
+}
+
+/// CubeMap renders the six faces of a cube around the camera
+///
+/// The faces are laid out in a 3:2 grid, with +X, -X and +Y across the top
+/// row and -Y, +Z and -Z across the bottom row, each oriented as in an
+/// OpenGL cube map. The axes are those of the camera's basis, so with the
+/// default placement -Z faces the lookat point.
+#[derive(Clone)]
+pub struct CubeMap {
+    placement: Placement,
+    exposure: f32,
+}
+
+impl CubeMap {
+    pub fn new(placement: Placement) -> CubeMap {
+        CubeMap { placement,
+                  exposure: 1.0 }
+    }
+
+    /// Expose the panorama like the camera it stands in for
+    pub fn with_exposure(mut self, exposure: f32) -> CubeMap {
+        self.exposure = exposure;
+        self
+    }
+}
+
+impl Camera for CubeMap {
+    fn generate_ray(&self, s: f32, t: f32, rng: &mut ThreadRng) -> Option<CameraSample> {
+        let column = ((3.0 * s) as usize).min(2);
+        let row = ((2.0 * (1.0 - t)) as usize).min(1);
+
+        // coordinates on the face from -1 to 1, with b pointing down the image
+        let a = 2.0 * (3.0 * s - column as f32) - 1.0;
+        let b = 2.0 * (2.0 * (1.0 - t) - row as f32) - 1.0;
+
+        let direction = match 3 * row + column {
+            0 => Vec3::new(1.0, -b, -a),
+            1 => Vec3::new(-1.0, -b, a),
+            2 => Vec3::new(a, 1.0, b),
+            3 => Vec3::new(a, -1.0, -b),
+            4 => Vec3::new(a, -b, 1.0),
+            _ => Vec3::new(-a, -b, -1.0),
+        };
+        Some(CameraSample::new(Ray::new(self.placement.origin,
+                                        self.placement.to_world(direction),
+                                        self.placement.sample_time(rng))))
+    }
+
+    fn placement(&self) -> &Placement {
+        &self.placement
+    }
+
+    fn placed(&self, placement: Placement) -> Box<dyn Camera> {
+        Box::new(CubeMap::new(placement).with_exposure(self.exposure))
+    }
+
+    fn exposure(&self) -> f32 {
+        self.exposure
+    }
+
+    /// Each face spans two units at unit distance across half the height of the image
+    fn spread_angle(&self, _width: usize, height: usize) -> f32 {
+        4.0 / height as f32
+    }
+}
+
+/// The arrangement of the two eyes in a stereo image
+#[derive(Clone, Copy)]
+pub enum StereoLayout {
+    /// The left eye fills the left half of the image
+    SideBySide,
+    /// The left eye fills the top half of the image
+    OverUnder,
+}
+
+/// Stereo renders the views of a left and a right eye into one image
+///
+/// The eyes sit interocular apart on either side of the camera they wrap.
+/// Objects at the convergence distance appear at the same place for both
+/// eyes, closer objects come out of the screen and farther ones recede
+/// into it, and an infinite convergence keeps the eyes parallel. Planar
+/// projections shear the view of each eye so both image planes stay
+/// parallel instead of toeing the eyes in. Omnidirectional stereo turns
+/// the eyes around the origin with the direction of each ray, so every
+/// direction of a panorama sees a stereo pair, and brings them together
+/// toward the poles where no turn of the head keeps them level.
+///
+/// Reference:
+/// Google
+/// Rendering Omni-directional Stereo Content
+/// Jump Developer Documentation
+pub struct Stereo {
+    camera: Box<dyn Camera>,
+    interocular: f32,
+    convergence: f32,
+    layout: StereoLayout,
+    omnidirectional: bool,
+}
+
+impl Stereo {
+    pub fn new(camera: Box<dyn Camera>,
+               interocular: f32,
+               convergence: f32,
+               layout: StereoLayout)
+               -> Stereo {
+        Stereo { camera,
+                 interocular,
+                 convergence,
+                 layout,
+                 omnidirectional: false }
+    }
+
+    pub fn omnidirectional(camera: Box<dyn Camera>,
+                           interocular: f32,
+                           convergence: f32,
+                           layout: StereoLayout)
+                           -> Stereo {
+        Stereo { omnidirectional: true,
+                 ..Stereo::new(camera, interocular, convergence, layout) }
+    }
+}
+
+impl Camera for Stereo {
+    fn generate_ray(&self, s: f32, t: f32, rng: &mut ThreadRng) -> Option<CameraSample> {
+        // the side of the eye along u, with the left eye on the left or on top
+        let (eye, s, t) = match self.layout {
+            StereoLayout::SideBySide if s < 0.5 => (-0.5, 2.0 * s, t),
+            StereoLayout::SideBySide => (0.5, 2.0 * s - 1.0, t),
+            StereoLayout::OverUnder if t >= 0.5 => (-0.5, s, 2.0 * t - 1.0),
+            StereoLayout::OverUnder => (0.5, s, 2.0 * t),
+        };
+
+        let mut sample = self.camera.generate_ray(s, t, rng)?;
+        let placement = self.camera.placement();
+        let (origin, direction) = (sample.ray.origin, sample.ray.direction);
+
+        let (origin, direction) = if self.omnidirectional {
+            // the eye moves along the horizontal tangent of a circle around the origin
+            let offset = eye * self.interocular * direction.cross(placement.v);
+            (origin + offset, direction - offset / self.convergence)
+        } else {
+            let offset = eye * self.interocular * placement.u;
+            let forward = -placement.w;
+            let depth = (origin - placement.origin).dot(forward);
+            (origin + offset * (1.0 - depth / self.convergence),
+             direction - offset * direction.dot(forward) / self.convergence)
+        };
+
+        sample.ray = Ray::new(origin, direction, sample.ray.time);
+        Some(sample)
+    }
+
+    fn placement(&self) -> &Placement {
+        self.camera.placement()
+    }
+
+    fn placed(&self, placement: Placement) -> Box<dyn Camera> {
+        Box::new(Stereo { camera: self.camera.placed(placement),
+                          ..*self })
+    }
+
+    /// Pass the frame to the wrapped camera, which may take its shutter interval
+    fn frame(&self, start_time: f32, end_time: f32) -> Box<dyn Camera> {
+        Box::new(Stereo { camera: self.camera.frame(start_time, end_time),
+                          ..*self })
+    }
+
+    fn exposure(&self) -> f32 {
+        self.camera.exposure()
+    }
+
+    fn spread_angle(&self, width: usize, height: usize) -> f32 {
+        match self.layout {
+            StereoLayout::SideBySide => self.camera.spread_angle(width / 2, height),
+            StereoLayout::OverUnder => self.camera.spread_angle(width, height / 2),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use materials::Empty;
+    use rand::thread_rng;
+    use sphere::Sphere;
+
+    #[test]
+    fn test_projections_look_toward_lookat() {
+        let mut rng = thread_rng();
+        let placement = Placement::new(Vec3::new(1.0, 2.0, 3.0),
+                                       Vec3::new(4.0, 2.0, -1.0),
+                                       Vec3::new(0.0, 1.0, 0.0),
+                                       0.0,
+                                       1.0,
+                                       false);
+        let forward = -placement.w;
+
+        let cameras: Vec<(Box<dyn Camera>, f32, f32)> =
+            vec![(Box::new(Perspective::new(placement.clone(), 40.0, 1.5, 0.0, 1.0)), 0.5, 0.5),
+                 (Box::new(Orthographic::new(placement.clone(), 2.0, 1.0)), 0.5, 0.5),
+                 (Box::new(Fisheye::new(placement.clone(),
+                                        180.0,
+                                        1.0,
+                                        FisheyeMapping::Equisolid)),
+                  0.5,
+                  0.5),
+                 (Box::new(Equirectangular::new(placement.clone())), 0.5, 0.5),
+                 (Box::new(CubeMap::new(placement.clone())), 5.0 / 6.0, 0.25)];
+
+        for (camera, s, t) in cameras {
+            let ray = camera.generate_ray(s, t, &mut rng).unwrap().ray;
+            assert!((ray.direction - forward).length() < 1e-4);
+        }
+
+        // a 50mm lens on a full frame sensor sees 39.6 degrees across its width
+        let physical =
+            Physical::new(placement.clone(), 1.5, 50.0, Sensor::full_frame(), 1000.0, 1.0);
+        let ray = physical.generate_ray(1.0, 0.5, &mut rng).unwrap().ray;
+        assert!((ray.direction.dot(forward).acos().to_degrees() - 19.8).abs() < 0.1);
+
+        // the right edge of a 180 degree fisheye looks along u
+        let fisheye = Fisheye::new(placement.clone(), 180.0, 1.0, FisheyeMapping::Equidistant);
+        let ray = fisheye.generate_ray(1.0, 0.5, &mut rng).unwrap().ray;
+        assert!((ray.direction - placement.u).length() < 1e-4);
+        assert!(fisheye.generate_ray(1.0, 1.0, &mut rng).is_none());
+    }
+
+    #[test]
+    fn test_autofocus_finds_targets() {
+        let placement = Placement::new(Vec3::new(0.0, 0.0, 0.0),
+                                       Vec3::new(0.0, 0.0, -1.0),
+                                       Vec3::new(0.0, 1.0, 0.0),
+                                       0.0,
+                                       1.0,
+                                       false);
+        let sphere: Arc<dyn Hitable> = Arc::new(Sphere::new(Vec3::new(0.0, 0.0, -5.0),
+                                                            Vec3::new(0.0, 0.0, -5.0),
+                                                            1.0,
+                                                            Empty::new(),
+                                                            0.0,
+                                                            1.0));
+        let camera = Perspective::new(placement, 40.0, 1.0, 0.1, 1.0);
+
+        let focused = camera.clone().with_autofocus(&*sphere, &Focus::Pixel(0.5, 0.5));
+        assert!((focused.focus_distance - 4.0).abs() < 1e-4);
+
+        let focused = camera.clone().with_autofocus(&*sphere, &Focus::Object(sphere.clone()));
+        assert!((focused.focus_distance - 4.0).abs() < 1e-4);
+
+        let point = Focus::Point(Vec3::new(1.0, 2.0, -3.0));
+        let focused = camera.clone().with_autofocus(&*sphere, &point);
+        assert!((focused.focus_distance - 3.0).abs() < 1e-4);
+
+        // a pixel that sees nothing keeps the focus distance
+        let focused = camera.with_autofocus(&*sphere, &Focus::Pixel(0.0, 0.0));
+        assert!((focused.focus_distance - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_stereo_eyes_converge() {
+        let mut rng = thread_rng();
+        let placement = Placement::new(Vec3::new(0.0, 0.0, 0.0),
+                                       Vec3::new(0.0, 0.0, -1.0),
+                                       Vec3::new(0.0, 1.0, 0.0),
+                                       0.0,
+                                       1.0,
+                                       false);
+        let convergence = Vec3::new(0.0, 0.0, -4.0);
+
+        // the center of each eye looks from its side of the origin toward the convergence point
+        let perspective = Box::new(Perspective::new(placement.clone(), 40.0, 1.0, 0.0, 1.0));
+        let stereo = Stereo::new(perspective, 0.2, 4.0, StereoLayout::SideBySide);
+        for &(s, side) in &[(0.25, -0.1), (0.75, 0.1)] {
+            let ray = stereo.generate_ray(s, 0.5, &mut rng).unwrap().ray;
+            assert!((ray.origin - Vec3::new(side, 0.0, 0.0)).length() < 1e-4);
+            assert!((ray.direction - (convergence - ray.origin).normalize()).length() < 1e-4);
+        }
+
+        let panorama = Box::new(Equirectangular::new(placement));
+        let stereo = Stereo::omnidirectional(panorama, 0.2, 4.0, StereoLayout::OverUnder);
+        for &(t, side) in &[(0.75, -0.1), (0.25, 0.1)] {
+            let ray = stereo.generate_ray(0.5, t, &mut rng).unwrap().ray;
+            assert!((ray.origin - Vec3::new(side, 0.0, 0.0)).length() < 1e-4);
+            assert!((ray.direction - (convergence - ray.origin).normalize()).length() < 1e-4);
+        }
+
+        // looking straight up both eyes meet at the origin
+        let ray = stereo.generate_ray(0.5, 1.0, &mut rng).unwrap().ray;
+        assert!(ray.origin.length() < 1e-4);
+    }
+
+    fn lens_camera(aperture: f32, lens: Lens) -> Perspective {
+        let placement = Placement::new(Vec3::new(0.0, 0.0, 0.0),
+                                       Vec3::new(0.0, 0.0, -1.0),
+                                       Vec3::new(0.0, 1.0, 0.0),
+                                       0.0,
+                                       1.0,
+                                       false);
+        Perspective::new(placement, 40.0, 1.0, aperture, 5.0).with_lens(lens)
+    }
+
+    #[test]
+    fn test_tilted_lens_focuses_on_tilted_plane() {
+        let mut rng = thread_rng();
+        let camera = lens_camera(1.0, Lens::new().with_tilt(20.0, 0.0));
+        let center = Vec3::new(0.0, 0.0, -5.0);
+        let normal = camera.focus_normal;
+        assert!(normal.dot(Vec3::new(0.0, 0.0, -1.0)) < 0.95);
+
+        // every ray through a pixel crosses the plane in focus at the same point
+        let mut depths = Vec::new();
+        for &t in &[0.2, 0.8] {
+            let focus = |ray: Ray| {
+                let distance = (center - ray.origin).dot(normal) / ray.direction.dot(normal);
+                ray.origin + distance * ray.direction
+            };
+            let point = focus(camera.generate_ray(0.5, t, &mut rng).unwrap().ray);
+            for _ in 0..100 {
+                let ray = camera.generate_ray(0.5, t, &mut rng).unwrap().ray;
+                assert!((focus(ray) - point).length() < 1e-3);
+            }
+            depths.push(point.z());
+        }
+
+        // so the distance in focus changes up the image
+        assert!((depths[0] - depths[1]).abs() > 0.5);
+    }
+
+    #[test]
+    fn test_cat_eye_clips_corners() {
+        let mut rng = thread_rng();
+        let camera = lens_camera(1.0, Lens::new().with_cat_eye(1.0));
+
+        let center = (0..1000).filter(|_| camera.generate_ray(0.5, 0.5, &mut rng).is_none());
+        assert_eq!(center.count(), 0);
+        let corner = (0..1000).filter(|_| camera.generate_ray(0.0, 0.0, &mut rng).is_none());
+        assert!(corner.count() > 0);
+    }
+
+    #[test]
+    fn test_chromatic_aberration_scales_each_channel() {
+        let mut rng = thread_rng();
+        let scales = Vec3::new(0.9, 1.0, 1.1);
+        let camera = lens_camera(0.0, Lens::new().with_chromatic_aberration(scales));
+        let pinhole = lens_camera(0.0, Lens::new());
+
+        let mut seen = [false; 3];
+        for _ in 0..100 {
+            let sample = camera.generate_ray(0.9, 0.8, &mut rng).unwrap();
+            let channels: Vec<usize> = (0..3).filter(|&i| sample.weight.as_ref()[i] != 0.0)
+                                             .collect();
+            assert_eq!(channels.len(), 1);
+            let channel = channels[0];
+            assert_eq!(sample.weight.as_ref()[channel], 3.0);
+            seen[channel] = true;
+
+            // each channel sees the image scaled around its center
+            let scale = scales.as_ref()[channel];
+            let expected = pinhole.generate_ray(0.5 + scale * 0.4, 0.5 + scale * 0.3, &mut rng)
+                                  .unwrap()
+                                  .ray;
+            let direction = sample.ray.direction.normalize();
+            assert!((direction - expected.direction.normalize()).length() < 1e-5);
+        }
+        assert!(seen.iter().all(|&seen| seen));
+    }
+
+    #[test]
+    fn test_physical_frames_take_their_shutter_interval() {
+        let placement = Placement::new(Vec3::new(0.0, 0.0, 0.0),
+                                       Vec3::new(0.0, 0.0, -1.0),
+                                       Vec3::new(0.0, 1.0, 0.0),
+                                       0.0,
+                                       1.0,
+                                       false);
+        let camera = Physical::new(placement, 1.0, 50.0, Sensor::full_frame(), 8.0, 5.0)
+                         .with_shutter_speed(1.0 / 60.0);
+
+        // a still keeps its shutter open for the shutter speed
+        assert!((camera.placement().end_time - 1.0 / 60.0).abs() < 1e-6);
+
+        // a frame exposes for the interval the sequence gives it
+        let frame = camera.frame(1.0, 1.01);
+        assert!((frame.placement().start_time - 1.0).abs() < 1e-6);
+        assert!((frame.placement().end_time - 1.01).abs() < 1e-6);
+        let still = camera.clone().with_shutter_speed(0.01);
+        assert!((frame.exposure() - still.exposure()).abs() < 1e-4 * still.exposure());
+
+        // and so does the same camera behind a stereo pair
+        let stereo = Stereo::new(Box::new(camera), 0.1, 5.0, StereoLayout::SideBySide);
+        let frame = stereo.frame(1.0, 1.01);
+        assert!((frame.placement().end_time - 1.01).abs() < 1e-6);
+        assert!((frame.exposure() - still.exposure()).abs() < 1e-4 * still.exposure());
     }
 }
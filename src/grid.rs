@@ -0,0 +1,348 @@
+use std::f32;
+use std::fs;
+
+use glam::{Mat4, Vec3};
+
+use aabb::AABB;
+use ray::Ray;
+
+/// The number of voxels along each side of a brick
+const BRICK_SIZE: usize = 8;
+
+/// VoxelGrid is a dense grid of scalar values such as the density of smoke
+///
+/// The grid spans its bounds in its own local space and is placed in the
+/// world with a transform. The grid is divided into bricks of 8x8x8 voxels
+/// that each store the maximum value that can be interpolated inside them,
+/// which gives tight majorants when tracking rays through the grid.
+#[derive(Clone)]
+pub struct VoxelGrid {
+    pub resolution: [usize; 3],
+    pub bounds: AABB,
+    pub data: Vec<f32>,
+    pub maximum: f32,
+    transform: Mat4,
+    inverse: Mat4,
+    bricks: [usize; 3],
+    brick_maximums: Vec<f32>,
+}
+
+impl VoxelGrid {
+    /// Create a new VoxelGrid spanning the given bounds
+    ///
+    /// data is stored with x varying fastest, then y, then z. Values are
+    /// placed at the centers of the voxels and interpolated trilinearly.
+    pub fn new(resolution: [usize; 3], bounds: AABB, data: Vec<f32>) -> VoxelGrid {
+        assert_eq!(data.len(),
+                   resolution[0] * resolution[1] * resolution[2],
+                   "VoxelGrid data does not match its resolution");
+
+        let maximum = data.iter().cloned().fold(0.0, f32::max);
+        let bricks = [resolution[0].div_ceil(BRICK_SIZE),
+                      resolution[1].div_ceil(BRICK_SIZE),
+                      resolution[2].div_ceil(BRICK_SIZE)];
+
+        let mut grid = VoxelGrid { resolution,
+                                   bounds,
+                                   data,
+                                   maximum,
+                                   transform: Mat4::identity(),
+                                   inverse: Mat4::identity(),
+                                   bricks,
+                                   brick_maximums: Vec::new() };
+        grid.brick_maximums = grid.compute_brick_maximums();
+        grid
+    }
+
+    /// Load the first channel of a grid in the Mitsuba .vol format
+    ///
+    /// The file starts with the bytes 'VOL' and version 3, followed by the
+    /// encoding, the resolution, the channel count and the bounding box.
+    /// Only single precision data is supported.
+    ///
+    /// Reference: https://www.mitsuba-renderer.org/releases/current/documentation.pdf
+    pub fn from_vol(filename: &str) -> VoxelGrid {
+        let bytes = fs::read(filename).unwrap();
+        assert!(bytes.len() >= 48 && &bytes[0..3] == b"VOL" && bytes[3] == 3,
+                "{} is not a version 3 .vol file",
+                filename);
+
+        let int = |offset: usize| read_u32(&bytes, offset) as usize;
+        let float = |offset: usize| f32::from_bits(read_u32(&bytes, offset));
+
+        assert_eq!(int(4), 1, "only float32 .vol files are supported");
+        let resolution = [int(8), int(12), int(16)];
+        let channels = int(20);
+        let bounds = AABB::from(Vec3::new(float(24), float(28), float(32)),
+                                Vec3::new(float(36), float(40), float(44)));
+
+        let count = resolution[0] * resolution[1] * resolution[2];
+        assert!(bytes.len() >= 48 + 4 * count * channels, "{} is truncated", filename);
+
+        let data = (0..count).map(|i| float(48 + 4 * i * channels)).collect();
+
+        VoxelGrid::new(resolution, bounds, data)
+    }
+
+    /// Load a raw dense grid of floats spanning the unit cube
+    ///
+    /// The header holds the x, y and z resolutions as little endian 32 bit
+    /// integers and is followed by little endian 32 bit floats with x
+    /// varying fastest. Use with_transform to place the grid in the scene.
+    pub fn from_raw(filename: &str) -> VoxelGrid {
+        let bytes = fs::read(filename).unwrap();
+        assert!(bytes.len() >= 12, "{} is missing its header", filename);
+
+        let resolution = [read_u32(&bytes, 0) as usize,
+                          read_u32(&bytes, 4) as usize,
+                          read_u32(&bytes, 8) as usize];
+        let count = resolution[0] * resolution[1] * resolution[2];
+        assert!(bytes.len() >= 12 + 4 * count, "{} is truncated", filename);
+
+        let data = (0..count).map(|i| f32::from_bits(read_u32(&bytes, 12 + 4 * i))).collect();
+
+        VoxelGrid::new(resolution, AABB::from(Vec3::zero(), Vec3::one()), data)
+    }
+
+    /// Place the grid in the world with the given transform
+    pub fn with_transform(mut self, transform: Mat4) -> VoxelGrid {
+        self.transform = transform;
+        self.inverse = transform.inverse();
+        self
+    }
+
+    /// The bounding box of the placed grid in world space
+    pub fn world_bounds(&self) -> AABB {
+        let (minimum, maximum) = (self.bounds.minimum, self.bounds.maximum);
+        let mut bounds = AABB::new();
+
+        for i in 0..8 {
+            let corner = Vec3::new(if i & 1 == 0 { minimum.x() } else { maximum.x() },
+                                   if i & 2 == 0 { minimum.y() } else { maximum.y() },
+                                   if i & 4 == 0 { minimum.z() } else { maximum.z() });
+            let corner = self.transform.transform_point3(corner);
+            bounds = bounds.surrounding_box(&AABB::from(corner, corner));
+        }
+
+        bounds
+    }
+
+    fn voxel(&self, x: usize, y: usize, z: usize) -> f32 {
+        self.data[(z * self.resolution[1] + y) * self.resolution[0] + x]
+    }
+
+    /// Find the maximum of each brick including a border of one voxel
+    ///
+    /// Trilinear interpolation blends with the voxels just outside of a
+    /// brick, so they must be included for the maximum to be a bound.
+    fn compute_brick_maximums(&self) -> Vec<f32> {
+        let [bx, by, bz] = self.bricks;
+        let mut maximums = vec![0.0; bx * by * bz];
+
+        let range = |brick: usize, axis: usize| {
+            let start = (brick * BRICK_SIZE).saturating_sub(1);
+            let end = ((brick + 1) * BRICK_SIZE + 1).min(self.resolution[axis]);
+            start..end
+        };
+
+        for k in 0..bz {
+            for j in 0..by {
+                for i in 0..bx {
+                    let mut maximum: f32 = 0.0;
+                    for z in range(k, 2) {
+                        for y in range(j, 1) {
+                            for x in range(i, 0) {
+                                maximum = maximum.max(self.voxel(x, y, z));
+                            }
+                        }
+                    }
+                    maximums[(k * by + j) * bx + i] = maximum;
+                }
+            }
+        }
+
+        maximums
+    }
+
+    /// Interpolate the grid at the given point in world space
+    ///
+    /// Points outside of the grid's bounds have a value of zero.
+    pub fn lookup(&self, p: &Vec3) -> f32 {
+        let extent = self.bounds.maximum - self.bounds.minimum;
+        let local = (self.inverse.transform_point3(*p) - self.bounds.minimum) / extent;
+
+        if local.min_element() < 0.0 || local.max_element() > 1.0 {
+            return 0.0;
+        }
+
+        let mut indices = [0usize; 3];
+        let mut next = [0usize; 3];
+        let mut fractions = [0.0f32; 3];
+
+        for axis in 0..3 {
+            let size = self.resolution[axis];
+            let position = (local.as_ref()[axis] * size as f32 - 0.5).max(0.0)
+                                                                      .min((size - 1) as f32);
+            indices[axis] = position as usize;
+            next[axis] = (indices[axis] + 1).min(size - 1);
+            fractions[axis] = position - indices[axis] as f32;
+        }
+
+        let lerp = |t: f32, a: f32, b: f32| a + t * (b - a);
+        let [x0, y0, z0] = indices;
+        let [x1, y1, z1] = next;
+        let [dx, dy, dz] = fractions;
+
+        lerp(dz,
+             lerp(dy,
+                  lerp(dx, self.voxel(x0, y0, z0), self.voxel(x1, y0, z0)),
+                  lerp(dx, self.voxel(x0, y1, z0), self.voxel(x1, y1, z0))),
+             lerp(dy,
+                  lerp(dx, self.voxel(x0, y0, z1), self.voxel(x1, y0, z1)),
+                  lerp(dx, self.voxel(x0, y1, z1), self.voxel(x1, y1, z1))))
+    }
+
+    /// Visit the bricks along a ray up to t_max in order
+    ///
+    /// The visitor receives the parametric range of the ray inside each
+    /// brick and the brick's maximum, and returns false to stop early.
+    /// Parts of the ray outside of the grid are skipped as the grid is zero
+    /// there. The bricks are walked with a 3D digital differential analyzer.
+    ///
+    /// Reference:
+    /// John Amanatides, Andrew Woo
+    /// A Fast Voxel Traversal Algorithm for Ray Tracing
+    /// Eurographics 1987
+    pub fn traverse_bricks<F>(&self, ray: &Ray, t_max: f32, mut visit: F)
+        where F: FnMut(f32, f32, f32) -> bool
+    {
+        let extent = self.bounds.maximum - self.bounds.minimum;
+        let scale = Vec3::new(self.resolution[0] as f32,
+                              self.resolution[1] as f32,
+                              self.resolution[2] as f32)
+                    / (extent * BRICK_SIZE as f32);
+
+        let origin = (self.inverse.transform_point3(ray.origin) - self.bounds.minimum) * scale;
+        let direction = self.inverse.transform_vector3(ray.direction) * scale;
+        let upper = Vec3::new(self.resolution[0] as f32,
+                              self.resolution[1] as f32,
+                              self.resolution[2] as f32)
+                    / BRICK_SIZE as f32;
+
+        let (mut t_enter, mut t_exit) = (0.0f32, t_max);
+        for axis in 0..3 {
+            let inverse = 1.0 / direction.as_ref()[axis];
+            let mut t0 = -origin.as_ref()[axis] * inverse;
+            let mut t1 = (upper.as_ref()[axis] - origin.as_ref()[axis]) * inverse;
+            if inverse < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            if t0.is_nan() || t1.is_nan() {
+                continue;
+            }
+            t_enter = t_enter.max(t0);
+            t_exit = t_exit.min(t1);
+        }
+        if t_enter >= t_exit {
+            return;
+        }
+
+        let entry = origin + t_enter * direction;
+        let mut cell = [0isize; 3];
+        let mut step = [0isize; 3];
+        let mut t_next = [f32::INFINITY; 3];
+        let mut t_delta = [f32::INFINITY; 3];
+
+        for axis in 0..3 {
+            let bricks = self.bricks[axis] as isize;
+            let d = direction.as_ref()[axis];
+            cell[axis] = (entry.as_ref()[axis].floor() as isize).max(0).min(bricks - 1);
+
+            if d > 0.0 {
+                step[axis] = 1;
+                t_delta[axis] = 1.0 / d;
+                t_next[axis] = t_enter + ((cell[axis] + 1) as f32 - entry.as_ref()[axis]) / d;
+            } else if d < 0.0 {
+                step[axis] = -1;
+                t_delta[axis] = -1.0 / d;
+                t_next[axis] = t_enter + (cell[axis] as f32 - entry.as_ref()[axis]) / d;
+            }
+        }
+
+        let mut t = t_enter;
+        while t < t_exit {
+            let axis = if t_next[0] < t_next[1] && t_next[0] < t_next[2] {
+                0
+            } else if t_next[1] < t_next[2] {
+                1
+            } else {
+                2
+            };
+
+            let end = t_next[axis].min(t_exit);
+            let [bx, by, _] = self.bricks;
+            let index = (cell[2] as usize * by + cell[1] as usize) * bx + cell[0] as usize;
+
+            if !visit(t, end, self.brick_maximums[index]) {
+                return;
+            }
+
+            t = end;
+            cell[axis] += step[axis];
+            t_next[axis] += t_delta[axis];
+            if cell[axis] < 0 || cell[axis] >= self.bricks[axis] as isize {
+                return;
+            }
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bricks_bound_lookups() {
+        let resolution = [20, 12, 9];
+        let data = (0..20 * 12 * 9).map(|i| ((i * 7919) % 101) as f32 / 100.0).collect();
+        let grid = VoxelGrid::new(resolution,
+                                  AABB::from(Vec3::zero(), Vec3::new(2.0, 1.0, 1.0)),
+                                  data).with_transform(Mat4::from_translation(Vec3::one()));
+
+        let ray = Ray::new(Vec3::new(0.5, 1.1, 1.2), Vec3::new(1.0, 0.3, 0.2), 0.0);
+        let mut previous = 0.0;
+
+        grid.traverse_bricks(&ray, 10.0, |start, end, maximum| {
+            assert!(start >= previous && end >= start);
+            for i in 0..=8 {
+                let t = start + (end - start) * i as f32 / 8.0;
+                assert!(grid.lookup(&ray.point_at_parameter(t)) <= maximum + 1e-5);
+            }
+            previous = end;
+            true
+        });
+
+        assert!(previous > 0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "is truncated")]
+    fn test_truncated_vol_is_rejected() {
+        let mut bytes = b"VOL".to_vec();
+        bytes.push(3);
+        for value in &[1u32, 2, 2, 2, 1] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in &[0.0f32, 0.0, 0.0, 1.0, 1.0, 1.0, 0.5] {
+            bytes.extend_from_slice(&value.to_bits().to_le_bytes());
+        }
+
+        let path = std::env::temp_dir().join("renderama_truncated.vol");
+        fs::write(&path, bytes).unwrap();
+        VoxelGrid::from_vol(path.to_str().unwrap());
+    }
+}
@@ -5,10 +5,12 @@ use rand::rngs::ThreadRng;
 
 use aabb::AABB;
 use materials::Material;
+use noise::{hash, to_unit};
 use ray::Ray;
 
 /// HitRecord contains the elements necessary to render geometry
 /// once a ray has hit that geometry.
+#[derive(Clone)]
 pub struct HitRecord {
     pub parameter: f32,
     pub u: f32,
@@ -16,11 +18,17 @@ pub struct HitRecord {
     pub point: Vec3,
     pub geometric_normal: Vec3,
     pub shading_normal: Vec3,
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
+    pub footprint: f32,
     pub material: Arc<dyn Material>,
 }
 
 impl HitRecord {
     /// Create a new HitRecord for a given ray-geometry intersection.
+    ///
+    /// The surface derivatives are left at zero until the geometry
+    /// provides its own with `with_derivatives`.
     pub fn new(parameter: f32,
                u: f32,
                v: f32,
@@ -35,8 +43,63 @@ impl HitRecord {
                     point: point,
                     geometric_normal: geometric_normal,
                     shading_normal: shading_normal,
+                    dpdu: Vec3::zero(),
+                    dpdv: Vec3::zero(),
+                    footprint: 0.0,
                     material: material }
     }
+
+    /// Set the partial derivatives of the hit point with respect to the texture coordinates
+    ///
+    /// These span the tangent plane used by normal and bump mapping.
+    pub fn with_derivatives(mut self, dpdu: Vec3, dpdv: Vec3) -> HitRecord {
+        self.dpdu = dpdu;
+        self.dpdv = dpdv;
+        self
+    }
+
+    /// Set the width of the ray's cone at the hit in texture coordinates
+    ///
+    /// The width is stretched as the ray grazes the surface and divided by
+    /// the world size of a unit of texture coordinates. Hits without surface
+    /// derivatives keep a footprint of zero, the finest texture detail.
+    pub fn with_footprint(mut self, ray: &Ray) -> HitRecord {
+        let area = self.dpdu.cross(self.dpdv).length();
+        if area > 0.0 {
+            let cosine = ray.direction.dot(self.geometric_normal).abs().max(0.01);
+            self.footprint = ray.cone_width(self.parameter) / (cosine * area.sqrt());
+        }
+        self
+    }
+
+    /// Determine whether the hit stops the ray according to the material's opacity
+    ///
+    /// Fully transparent hits are always skipped and fractional opacity is
+    /// treated as the probability of stopping the ray. Since every ray,
+    /// including the ones cast toward lights, goes through intersection,
+    /// cutouts also shape the shadows they cast. The random number is a
+    /// hash of the ray and the hit, so the same ray always makes the same
+    /// choice however it is traced, as in pbrt-v4.
+    pub fn is_opaque(&self, ray: &Ray) -> bool {
+        let opacity = self.material.opacity(self);
+
+        if opacity >= 1.0 {
+            true
+        } else if opacity <= 0.0 {
+            false
+        } else {
+            let (origin, direction) = (ray.origin, ray.direction);
+            let values = [origin.x(),
+                          origin.y(),
+                          origin.z(),
+                          direction.x(),
+                          direction.y(),
+                          direction.z(),
+                          self.parameter];
+            let key = values.iter().fold(0, |key, value| hash(key ^ value.to_bits()));
+            to_unit(key) < opacity
+        }
+    }
 }
 
 /// The Hitable trait is a trait that all hitable objects will implement.
@@ -0,0 +1,89 @@
+use std::sync::Arc;
+
+use glam::Mat4;
+
+use aabb::AABB;
+use hitable::{HitRecord, Hitable};
+use materials::Material;
+use ray::Ray;
+use transformations::Affine;
+
+/// Instance places a shared prototype in the scene
+///
+/// The prototype, typically a TriangleMesh with its own BVH, is stored once
+/// and referenced by every instance. Each instance only holds a transform
+/// and an optional material replacing the prototype's materials, so the
+/// scene BVH is built over the instance bounds and many copies of a large
+/// mesh cost little more memory than one.
+pub struct Instance {
+    prototype: Arc<dyn Hitable>,
+    transform: Affine,
+    material: Option<Arc<dyn Material>>,
+}
+
+impl Instance {
+    /// Create a new instance of the prototype placed by the object to world transform
+    pub fn new(prototype: Arc<dyn Hitable>, transform: Mat4) -> Instance {
+        Instance { prototype,
+                   transform: Affine::new(transform),
+                   material: None }
+    }
+
+    /// Replace the materials of the prototype with the given material
+    ///
+    /// The opacity of the prototype's own materials still decides which
+    /// surfaces are hit.
+    pub fn with_material<M: Material + 'static>(mut self, material: M) -> Instance {
+        self.material = Some(Arc::new(material));
+        self
+    }
+}
+
+impl Hitable for Instance {
+    fn hit(&self, ray: &Ray, position_min: f32, position_max: f32) -> Option<HitRecord> {
+        let (object_ray, scale) = self.transform.ray_to_object(ray);
+        let hit = self.prototype
+                      .hit(&object_ray, position_min * scale, position_max * scale)?;
+
+        let mut hit = self.transform.hit_to_world(hit, scale);
+        if let Some(material) = &self.material {
+            hit.material = material.clone();
+        }
+        Some(hit)
+    }
+
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
+        self.prototype
+            .bounding_box(t0, t1)
+            .map(|bbox| self.transform.bounding_box(&bbox))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::{Quat, Vec3};
+
+    use materials::Empty;
+    use sphere::Sphere;
+
+    #[test]
+    fn test_instance_matches_transformed_sphere() {
+        let prototype: Arc<dyn Hitable> =
+            Arc::new(Sphere::new(Vec3::zero(), Vec3::zero(), 1.0, Empty::new(), 0.0, 1.0));
+        let center = Vec3::new(1.0, 2.0, -3.0);
+        let transform = Mat4::from_scale_rotation_translation(Vec3::splat(2.0),
+                                                              Quat::from_rotation_y(0.7),
+                                                              center);
+        let instance = Instance::new(prototype, transform);
+        let sphere = Sphere::new(center, center, 2.0, Empty::new(), 0.0, 1.0);
+
+        let ray = Ray::new(Vec3::new(0.0, 2.5, 10.0), Vec3::new(0.1, 0.0, -1.0), 0.0);
+        let expected = sphere.hit(&ray, 1e-4, f32::MAX).unwrap();
+        let found = instance.hit(&ray, 1e-4, f32::MAX).unwrap();
+
+        assert!((expected.parameter - found.parameter).abs() < 1e-4);
+        assert!((expected.point - found.point).length() < 1e-4);
+        assert!((expected.geometric_normal - found.geometric_normal).length() < 1e-4);
+    }
+}
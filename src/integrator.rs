@@ -7,10 +7,12 @@ use rand::Rng;
 use rand_distr::{Distribution, Normal};
 
 use bvh::BVH;
-use hitable::Hitable;
+use hitable::{HitRecord, Hitable};
+use medium::Medium;
 use pdf::PDF;
 use plane::Plane;
 use ray::{find_offset_point, Ray};
+use spectrum::Wavelengths;
 
 /// Pick a random point on the unit sphere
 ///
@@ -31,6 +33,144 @@ pub fn pick_sphere_point(rng: &mut ThreadRng) -> Vec3 {
     Vec3::new(x, y, z).normalize()
 }
 
+/// Find the media containing the given point
+///
+/// A ray is cast from the point and the interfaces of closed surfaces
+/// with an interior medium are recorded as it leaves them. Media that the
+/// ray leaves without having entered contain the point. They are returned
+/// from outermost to innermost so the result can seed the medium stack of
+/// render_path_integrator.
+pub fn media_at(world: &BVH, point: Vec3, time: f32) -> Vec<Arc<Medium>> {
+    let mut entered: Vec<Arc<Medium>> = Vec::new();
+    let mut containing: Vec<Arc<Medium>> = Vec::new();
+    let mut ray = Ray::new(point, Vec3::new(0.0, 1.0, 0.0), time);
+
+    for _ in 0..64 {
+        let hit_record = match world.hit(&ray, 1e-4, f32::MAX) {
+            Some(hit_record) => hit_record,
+            None => break,
+        };
+
+        if let Some(interior) = hit_record.material.interior() {
+            if ray.direction.dot(hit_record.geometric_normal) < 0.0 {
+                entered.push(interior);
+            } else if let Some(index) = entered.iter().rposition(|m| Arc::ptr_eq(m, &interior)) {
+                entered.remove(index);
+            } else {
+                containing.push(interior);
+            }
+        }
+        ray = Ray::new(hit_record.point, ray.direction, time);
+    }
+
+    containing.reverse();
+    containing
+}
+
+/// The maximum number of scattering events in media along a single path
+///
+/// Media scatter many times more often than surfaces, so these events are
+/// counted separately from bounces. Random walks in dense media like skin
+/// rely on Russian roulette to end long before reaching this limit.
+const MAX_MEDIUM_EVENTS: u32 = 1024;
+
+/// The surroundings every path of a render starts out in
+pub struct Environment {
+    /// Whether rays escaping the scene see a sky gradient instead of black
+    pub atmosphere: bool,
+    /// The media containing the camera from outermost to innermost
+    pub media: Vec<Arc<Medium>>,
+}
+
+impl Environment {
+    pub fn new(atmosphere: bool, media: Vec<Arc<Medium>>) -> Environment {
+        Environment { atmosphere, media }
+    }
+
+    /// Fill the whole scene with a medium such as atmospheric fog
+    ///
+    /// The fog is the outermost medium, so paths return to it whenever
+    /// they leave the media of closed surfaces.
+    pub fn with_fog(mut self, fog: Medium) -> Environment {
+        self.media.insert(0, Arc::new(fog));
+        self
+    }
+}
+
+/// Whether the ray's hit at the given parameter lies on the light source
+fn hits_light(light_source: &Plane, ray: &Ray, parameter: f32) -> bool {
+    let tolerance = 1e-3 * parameter.max(1.0);
+    light_source.hit(ray, 1e-4, f32::MAX)
+                .is_some_and(|light| (light.parameter - parameter).abs() < tolerance)
+}
+
+/// Estimate the light scattered toward the path at a point inside a medium
+///
+/// A point on the light source is sampled and a shadow ray is traced toward
+/// it. The shadow ray passes through transparent surfaces, switching media
+/// as it crosses their interfaces, and is attenuated by the transmittance of
+/// every medium along the way. The result is weighted by the phase function
+/// of the medium the point lies in.
+fn sample_light_in_medium(point: Vec3,
+                          direction: Vec3,
+                          world: &BVH,
+                          light_source: &Plane,
+                          media: &[Arc<Medium>],
+                          template: &Ray,
+                          rng: &mut ThreadRng)
+                          -> Vec3 {
+    let medium = match media.last() {
+        Some(medium) => medium,
+        None => return Vec3::zero(),
+    };
+
+    let to_light = light_source.pdf_random(point, rng);
+    let pdf = light_source.pdf_value(point, to_light);
+    if pdf <= 0.0 {
+        return Vec3::zero();
+    }
+
+    let mut shadow = Ray::new(point, to_light, template.time);
+    shadow.wavelength = template.wavelength;
+    let phase = medium.phase.evaluate(direction.dot(shadow.direction));
+
+    let mut media = media.to_vec();
+    let mut transmittance = Vec3::one();
+
+    for _ in 0..16 {
+        let hit_record = match world.hit(&shadow, 1e-4, f32::MAX) {
+            Some(hit_record) => hit_record,
+            None => return Vec3::zero(),
+        };
+
+        if let Some(medium) = media.last() {
+            transmittance *= medium.transmittance(&shadow, hit_record.parameter, rng);
+        }
+
+        if !hit_record.material.transparent() {
+            if !hits_light(light_source, &shadow, hit_record.parameter) {
+                return Vec3::zero();
+            }
+            let emitted = hit_record.material.emitted(&shadow, &hit_record);
+            return transmittance * emitted * phase / pdf;
+        }
+
+        if let Some(interior) = hit_record.material.interior() {
+            if shadow.direction.dot(hit_record.geometric_normal) < 0.0 {
+                media.push(interior);
+            } else if let Some(index) = media.iter().rposition(|m| Arc::ptr_eq(m, &interior)) {
+                media.remove(index);
+            }
+        }
+
+        let mut next = Ray::new(hit_record.point, shadow.direction, shadow.time);
+        next.wavelength = shadow.wavelength;
+        shadow = next;
+    }
+
+    Vec3::zero()
+}
+
 /// Compute the color of the surface that the ray has collided with
 ///
 /// If the ray hits an object in the world, the object is colored in relation
@@ -39,68 +179,173 @@ pub fn pick_sphere_point(rng: &mut ThreadRng) -> Vec3 {
 /// the color at the ray's hit point. The depth has been set to an arbitrary
 /// limit of 50 which can lead to bias rendering.
 ///
+/// The media the path travels through are kept on a stack, starting with
+/// the media of the environment. Each segment is tracked through the
+/// innermost medium, which may scatter the path before it reaches the next
+/// surface. Crossing a surface with an interior medium pushes or pops it.
+/// Scattering events in media are limited separately from surface bounces
+/// and sample the light source directly through the media in between, so
+/// the light source is not counted again when the next segment hits it.
+///
+/// When wavelengths are given, the path is traced in spectral mode: every RGB
+/// quantity is upsampled at the path's wavelengths and the spectral radiance
+/// is converted back to RGB once the path terminates.
+///
 pub fn render_path_integrator(mut ray: Ray,
                      world: &BVH,
                      bounces: u32,
                      light_source: &Plane,
-                     atmosphere: bool,
+                     environment: &Environment,
+                     mut wavelengths: Option<Wavelengths>,
                      rng: &mut ThreadRng)
                      -> Vec3 {
     let mut color = Vec3::zero();
     let mut throughput = Vec3::one();
+    let mut media = environment.media.clone();
+    let mut sampled_light = false;
 
-    for bounce in 0..=bounces {
-        if let Some(hit_record) = world.hit(&ray, 1e-4, f32::MAX) {
-            let emitted = hit_record.material.emitted(&ray, &hit_record);
-            color += throughput * emitted;
+    let spectrum = |color: Vec3, wavelengths: &Option<Wavelengths>| match wavelengths {
+        Some(wavelengths) => wavelengths.sample_rgb(color),
+        None => color,
+    };
 
-            if let Some(scatter_record) = hit_record.material.scatter(&ray, &hit_record, rng) {
-                if scatter_record.specular {
-                    throughput *= scatter_record.attenuation;
-                    ray = scatter_record.specular_ray;
-                } else {
-                    let hitable_pdf = PDF::HitablePDF { origin: hit_record.point,
-                                                        hitable: Arc::new(light_source.clone()) };
-                    let mixture_pdf = PDF::MixturePDF { cosine_pdf: &scatter_record.pdf,
-                                                        hitable_pdf: &hitable_pdf };
-
-                    let mut offset_point = hit_record.point;
-                    if hit_record.geometric_normal != hit_record.shading_normal {
-                        offset_point =
-                            find_offset_point(hit_record.point, hit_record.geometric_normal);
-                        offset_point += pick_sphere_point(rng);
+    let mut bounce = 0;
+    let mut medium_events = 0;
+    while bounce <= bounces && medium_events <= MAX_MEDIUM_EVENTS {
+        ray.wavelength = wavelengths.map(|wavelengths| wavelengths.hero());
+
+        let hit = world.hit(&ray, 1e-4, f32::MAX)
+                       .map(|hit_record| hit_record.with_footprint(&ray));
+
+        let mut scattered_in_medium = false;
+        if let Some(medium) = media.last() {
+            let t_max = hit.as_ref().map_or(f32::MAX, |hit_record| hit_record.parameter);
+            let sample = medium.sample(&ray, t_max, rng);
+            color += throughput * spectrum(sample.emission, &wavelengths);
+            throughput *= spectrum(sample.weight, &wavelengths);
+
+            if let Some(t) = sample.t {
+                let point = ray.point_at_parameter(t);
+                let direct = sample_light_in_medium(point,
+                                                    ray.direction,
+                                                    world,
+                                                    light_source,
+                                                    &media,
+                                                    &ray,
+                                                    rng);
+                color += throughput * spectrum(direct, &wavelengths);
+
+                let direction = medium.phase.sample(ray.direction, rng);
+                ray = Ray::new(point, direction, ray.time).with_cone(ray.cone_width(t),
+                                                                     ray.spread);
+                scattered_in_medium = true;
+                sampled_light = true;
+            }
+        }
+
+        if !scattered_in_medium {
+            if let Some(hit_record) = hit {
+                if !(sampled_light && hits_light(light_source, &ray, hit_record.parameter)) {
+                    let emitted = hit_record.material.emitted(&ray, &hit_record);
+                    color += throughput * spectrum(emitted, &wavelengths);
+                }
+                sampled_light = sampled_light && hit_record.material.transparent();
+
+                let incoming = ray.direction;
+                // the ray's cone keeps its spread through bounces, as it would off a flat mirror
+                let cone_width = ray.cone_width(hit_record.parameter);
+                if let Some(scatter_record) = hit_record.material.scatter(&ray, &hit_record, rng)
+                {
+                    let scatterer = scatter_record.scatterer.unwrap_or(&*hit_record.material);
+
+                    if scatter_record.specular {
+                        throughput *= spectrum(scatter_record.attenuation, &wavelengths);
+                        if scatterer.dispersive() {
+                            if let Some(wavelengths) = wavelengths.as_mut() {
+                                wavelengths.terminate_secondary(&mut throughput);
+                            }
+                        }
+                        ray = scatter_record.specular_ray.with_cone(cone_width, ray.spread);
+                    } else {
+                        let hitable_pdf =
+                            PDF::HitablePDF { origin: hit_record.point,
+                                              hitable: Arc::new(light_source.clone()) };
+                        let mixture_pdf = PDF::MixturePDF { cosine_pdf: &scatter_record.pdf,
+                                                            hitable_pdf: &hitable_pdf };
+
+                        let mut offset_point = hit_record.point;
+                        if hit_record.geometric_normal != hit_record.shading_normal {
+                            offset_point =
+                                find_offset_point(hit_record.point, hit_record.geometric_normal);
+                            offset_point += pick_sphere_point(rng);
+                        }
+                        let scattered =
+                            Ray::new(offset_point, mixture_pdf.generate(rng), ray.time);
+                        let pdf = mixture_pdf.value(scattered.direction);
+                        let scattering_pdf = match scatter_record.shading_normal {
+                            Some(shading_normal) => {
+                                let perturbed = HitRecord { shading_normal,
+                                                            ..hit_record.clone() };
+                                scatterer.scattering_pdf(&ray, &perturbed, &scattered)
+                            }
+                            None => scatterer.scattering_pdf(&ray, &hit_record, &scattered),
+                        };
+
+                        throughput *= (scattering_pdf
+                                       * spectrum(scatter_record.attenuation, &wavelengths))
+                                      / pdf;
+
+                        ray = scattered.with_cone(cone_width, ray.spread);
                     }
-                    let scattered = Ray::new(offset_point, mixture_pdf.generate(rng), ray.time);
-                    let pdf = mixture_pdf.value(scattered.direction);
-                    let scattering_pdf = hit_record.material
-                                                   .scattering_pdf(&ray, &hit_record, &scattered);
+                } else {
+                    break;
+                }
 
-                    throughput *= (scattering_pdf * scatter_record.attenuation) / pdf;
+                if let Some(interior) = hit_record.material.interior() {
+                    let normal = hit_record.geometric_normal;
+                    let (before, after) = (incoming.dot(normal), ray.direction.dot(normal));
 
-                    ray = scattered;
+                    if before < 0.0 && after < 0.0 {
+                        media.push(interior);
+                    } else if before > 0.0 && after > 0.0 {
+                        if let Some(index) = media.iter().rposition(|m| Arc::ptr_eq(m, &interior))
+                        {
+                            media.remove(index);
+                        }
+                    }
                 }
             } else {
+                if environment.atmosphere {
+                    let point: f32 = 0.5 * (ray.direction.y() + 1.0);
+                    let lerp =
+                        (1.0 - point) * Vec3::splat(1.0) + point * Vec3::new(0.5, 0.7, 1.0);
+                    color += throughput * spectrum(lerp, &wavelengths);
+                }
                 break;
             }
+        }
+
+        if scattered_in_medium {
+            medium_events += 1;
         } else {
-            if atmosphere {
-                let point: f32 = 0.5 * (ray.direction.y() + 1.0);
-                let lerp = (1.0 - point) * Vec3::splat(1.0) + point * Vec3::new(0.5, 0.7, 1.0);
-                color = throughput * lerp;
-            } else {
-                color = Vec3::zero();
-            }
+            bounce += 1;
         }
 
-        if bounce > 3 {
-            let roulette_factor = (1.0 - throughput.max_element()).max(0.05);
+        if bounce + medium_events > 4 {
+            // Random walks scatter hundreds of times, so a minimum termination
+            // probability per event would cut them short and add noise.
+            let minimum = if scattered_in_medium { 0.0 } else { 0.05 };
+            let roulette_factor = (1.0 - throughput.max_element()).max(minimum);
             if rng.gen::<f32>() < roulette_factor {
                 break;
             }
             throughput /= 1.0 - roulette_factor;
         }
     }
-    return color;
+    match wavelengths {
+        Some(wavelengths) => wavelengths.to_rgb(color),
+        None => color,
+    }
 }
 
 pub fn render_normals(ray: Ray, world: &BVH) -> Vec3 {
@@ -0,0 +1,217 @@
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use glam::{Vec2, Vec3};
+use rand::rngs::ThreadRng;
+use rand::Rng;
+
+use sampling::concentric_sample_disk;
+
+/// The shape of the opening in a lens that light passes through
+///
+/// The shape is given in units of the lens radius, so points on the disk
+/// lie within the unit circle. The out of focus highlights of a render,
+/// its bokeh, take on this shape.
+#[derive(Clone)]
+pub enum Aperture {
+    Disk,
+    /// A regular polygon inscribed in the unit circle, as formed by the blades of a diaphragm
+    Polygon { blades: u32, rotation: f32 },
+    Image(Arc<ApertureImage>),
+}
+
+impl Aperture {
+    /// Create a polygonal aperture rotated by the angle in degrees
+    pub fn polygon(blades: u32, rotation: f32) -> Aperture {
+        Aperture::Polygon { blades: blades.max(3),
+                            rotation: rotation.to_radians() }
+    }
+
+    /// Load a grayscale image of the aperture
+    ///
+    /// Brighter pixels let through more light. The image is stretched over
+    /// the square around the unit circle.
+    pub fn image(filename: &str) -> Aperture {
+        Aperture::Image(Arc::new(ApertureImage::new(filename)))
+    }
+
+    /// Sample a point on the aperture with density proportional to its transmission
+    pub fn sample(&self, rng: &mut ThreadRng) -> Vec2 {
+        match self {
+            Aperture::Disk => concentric_sample_disk(rng),
+            Aperture::Polygon { blades, rotation } => {
+                // every triangle between the center and an edge has the same area
+                let blade = rng.gen_range(0, *blades) as f32;
+                let angle = 2.0 * PI / *blades as f32;
+                let start = rotation + blade * angle;
+                let a = Vec2::new(start.cos(), start.sin());
+                let b = Vec2::new((start + angle).cos(), (start + angle).sin());
+
+                let r1 = rng.gen::<f32>().sqrt();
+                let r2 = rng.gen::<f32>();
+                r1 * (1.0 - r2) * a + r1 * r2 * b
+            }
+            Aperture::Image(image) => image.sample(rng),
+        }
+    }
+}
+
+/// A grayscale aperture with the distribution needed to sample it
+///
+/// Rows are chosen with the marginal distribution of their sums and the
+/// pixel within the row with the row's conditional distribution.
+pub struct ApertureImage {
+    width: usize,
+    height: usize,
+    marginal: Vec<f32>,
+    conditional: Vec<f32>,
+}
+
+impl ApertureImage {
+    pub fn new(filename: &str) -> ApertureImage {
+        let image = image::open(filename).unwrap().to_luma();
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let values: Vec<f32> = image.pixels().map(|pixel| pixel[0] as f32 / 255.0).collect();
+        ApertureImage::from_values(width, height, &values)
+    }
+
+    /// Build the distribution from the transmission of each pixel in rows from the top
+    fn from_values(width: usize, height: usize, values: &[f32]) -> ApertureImage {
+        let mut conditional = vec![0.0; width * height];
+        let mut marginal = vec![0.0; height];
+        let mut total = 0.0;
+
+        for y in 0..height {
+            let mut sum = 0.0;
+            for x in 0..width {
+                sum += values[y * width + x];
+                conditional[y * width + x] = sum;
+            }
+            if sum > 0.0 {
+                conditional[y * width..(y + 1) * width].iter_mut().for_each(|c| *c /= sum);
+            }
+            total += sum;
+            marginal[y] = total;
+        }
+        assert!(total > 0.0, "an aperture image must let some light through");
+        marginal.iter_mut().for_each(|m| *m /= total);
+
+        ApertureImage { width,
+                        height,
+                        marginal,
+                        conditional }
+    }
+
+    fn sample(&self, rng: &mut ThreadRng) -> Vec2 {
+        let u = rng.gen::<f32>();
+        let y = self.marginal.partition_point(|&m| m < u).min(self.height - 1);
+        let row = &self.conditional[y * self.width..(y + 1) * self.width];
+        let u = rng.gen::<f32>();
+        let x = row.partition_point(|&c| c < u).min(self.width - 1);
+
+        let s = (x as f32 + rng.gen::<f32>()) / self.width as f32;
+        let t = (y as f32 + rng.gen::<f32>()) / self.height as f32;
+        Vec2::new(2.0 * s - 1.0, 1.0 - 2.0 * t)
+    }
+}
+
+/// Lens describes the optical effects of a thin lens camera
+///
+/// cat_eye clips the aperture by the barrel of the lens toward the edges of
+/// the image, turning bokeh into lemon shapes and darkening the corners.
+/// Tilting the lens by the tilt angles in degrees around the horizontal and
+/// vertical axes of the image tilts the plane in focus. chromatic_aberration
+/// scales the image around its center separately for each color channel.
+#[derive(Clone)]
+pub struct Lens {
+    pub aperture: Aperture,
+    pub cat_eye: f32,
+    pub tilt: Vec2,
+    pub chromatic_aberration: Vec3,
+}
+
+impl Lens {
+    pub fn new() -> Lens {
+        Lens { aperture: Aperture::Disk,
+               cat_eye: 0.0,
+               tilt: Vec2::zero(),
+               chromatic_aberration: Vec3::one() }
+    }
+
+    pub fn with_aperture(mut self, aperture: Aperture) -> Lens {
+        self.aperture = aperture;
+        self
+    }
+
+    /// Clip the aperture toward the edges of the image
+    ///
+    /// At one, the aperture in the corners of the image is cut to the
+    /// overlap of two circles one radius apart.
+    pub fn with_cat_eye(mut self, cat_eye: f32) -> Lens {
+        self.cat_eye = cat_eye;
+        self
+    }
+
+    pub fn with_tilt(mut self, horizontal: f32, vertical: f32) -> Lens {
+        self.tilt = Vec2::new(horizontal, vertical);
+        self
+    }
+
+    /// Scale the image of the red, green and blue channels around its center
+    ///
+    /// Values slightly above and below one fringe edges toward the corners.
+    pub fn with_chromatic_aberration(mut self, scales: Vec3) -> Lens {
+        self.chromatic_aberration = scales;
+        self
+    }
+}
+
+impl Default for Lens {
+    fn default() -> Lens {
+        Lens::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::thread_rng;
+
+    #[test]
+    fn test_apertures_stay_in_shape() {
+        let mut rng = thread_rng();
+        let hexagon = Aperture::polygon(6, 0.0);
+        // a single open pixel in the top right quadrant of a 4x4 image
+        let mut values = vec![0.0; 16];
+        values[6] = 1.0;
+        let image = Aperture::Image(Arc::new(ApertureImage::from_values(4, 4, &values)));
+
+        for _ in 0..1000 {
+            assert!(Aperture::Disk.sample(&mut rng).length() <= 1.0);
+
+            // the apothem of a hexagon with a flat top is cos(30) of its radius
+            let point = hexagon.sample(&mut rng);
+            assert!(point.y().abs() <= (PI / 6.0).cos() + 1e-6);
+
+            let point = image.sample(&mut rng);
+            assert!(point.x() >= 0.0 && point.x() <= 0.5);
+            assert!(point.y() >= 0.0 && point.y() <= 0.5);
+        }
+    }
+
+    #[test]
+    fn test_aperture_image_samples_in_proportion_to_transmission() {
+        let mut rng = thread_rng();
+        // two open pixels at the ends of a row, the right one letting through three times the light
+        let image = ApertureImage::from_values(4, 1, &[0.25, 0.0, 0.0, 0.75]);
+
+        let samples = 100_000;
+        let mut counts = [0; 4];
+        for _ in 0..samples {
+            let x = image.sample(&mut rng).x();
+            counts[(2.0 * (x + 1.0)).min(3.0) as usize] += 1;
+        }
+        assert_eq!(counts[1] + counts[2], 0);
+        assert!((counts[3] as f32 / samples as f32 - 0.75).abs() < 0.01);
+    }
+}
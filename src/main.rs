@@ -16,9 +16,14 @@ mod basis;
 mod bvh;
 mod camera;
 mod denoise;
+mod grid;
 mod hitable;
+mod instance;
 mod integrator;
+mod lens;
 mod materials;
+mod medium;
+mod noise;
 mod pdf;
 mod plane;
 mod post;
@@ -26,6 +31,7 @@ mod ray;
 mod rectangle;
 mod sampling;
 mod scene;
+mod spectrum;
 mod sphere;
 mod texture;
 mod tone;
@@ -37,6 +43,8 @@ mod world;
 
 use std::env;
 use std::f32;
+use std::ops::RangeInclusive;
+use std::process;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::thread;
@@ -49,18 +57,95 @@ use pbr::ProgressBar;
 use rand::thread_rng;
 use rayon::prelude::*;
 
+use camera::{Camera, CubeMap, Equirectangular, Stereo, StereoLayout};
+use medium::Medium;
+
 #[cfg(feature = "denoise")]
 use denoise::denoise;
 
 fn main() {
     let rendering_time = Instant::now();
 
-    let (width, height): (usize, usize) = (2048, 2048);
     let args: Vec<String> = env::args().collect();
+    let projection = args.iter().find_map(|arg| arg.strip_prefix("--projection="));
+    let (width, height): (usize, usize) = match projection {
+        Some("equirectangular") => (2048, 1024),
+        Some("cubemap") => (3072, 2048),
+        _ => (2048, 2048),
+    };
     let samples: u32 = args[1].parse().unwrap();
     let bounces: u32 = 10;
+    let spectral = args.iter().any(|arg| arg == "--spectral");
+    let option = |name: &str| {
+        let value = args.iter().find_map(|arg| arg.strip_prefix(name))?;
+        Some(value.parse::<f32>().unwrap_or_else(|_| usage(name, "NUMBER", value)))
+    };
+    let fog_density = option("--fog=");
+    let fog_falloff = option("--fog-falloff=");
+    let frames = args.iter()
+                     .find_map(|arg| arg.strip_prefix("--frames="))
+                     .map(|range| {
+                         parse_frames(range).unwrap_or_else(|| {
+                                                usage("--frames=",
+                                                      "FIRST[:LAST] with 1 <= FIRST <= LAST",
+                                                      range)
+                                            })
+                     });
+    let fps = option("--fps=").unwrap_or(24.0);
+    if fps.is_nan() || fps <= 0.0 {
+        usage("--fps=", "NUMBER above zero", &fps.to_string());
+    }
+    let shutter = option("--shutter=").unwrap_or(0.5);
+
+    let (name, scene_camera, mut world, light_source) = scene::cornell_box_scene(width, height);
 
-    let (name, camera, world, light_source) = scene::cornell_box_scene(width, height);
+    // by default the eyes converge on the lookat point and sit a thirtieth of its
+    // distance apart, and panoramas render omnidirectional stereo for headsets
+    let layout = match args.iter().find_map(|arg| arg.strip_prefix("--stereo=")) {
+        Some("side-by-side") => Some(StereoLayout::SideBySide),
+        Some("over-under") => Some(StereoLayout::OverUnder),
+        _ => None,
+    };
+    let placement = scene_camera.placement();
+    let distance = (placement.lookat - placement.origin).length();
+    let convergence = option("--convergence=").unwrap_or(distance);
+    let interocular = option("--interocular=").unwrap_or(convergence / 30.0);
+    let (width, height) = match layout {
+        Some(StereoLayout::SideBySide) => (2 * width, height),
+        Some(StereoLayout::OverUnder) => (width, 2 * height),
+        None => (width, height),
+    };
+
+    // panoramas from the scene camera's position bake environment maps of the scene,
+    // exposed like the scene camera would be for the same frame
+    let view = |camera: Box<dyn Camera>| -> Box<dyn Camera> {
+        let placement = camera.placement().clone();
+        let exposure = camera.exposure();
+        let camera: Box<dyn Camera> = match projection {
+            Some("equirectangular") => {
+                Box::new(Equirectangular::new(placement).with_exposure(exposure))
+            }
+            Some("cubemap") => Box::new(CubeMap::new(placement).with_exposure(exposure)),
+            _ => camera,
+        };
+        match (layout, projection) {
+            (Some(layout), Some("equirectangular")) | (Some(layout), Some("cubemap")) => {
+                Box::new(Stereo::omnidirectional(camera, interocular, convergence, layout))
+            }
+            (Some(layout), _) => Box::new(Stereo::new(camera, interocular, convergence, layout)),
+            (None, _) => camera,
+        }
+    };
+
+    let fog = fog_density.map(|density| {
+                             let sigma_s = Vec3::splat(density);
+                             match fog_falloff {
+                                 Some(falloff) => {
+                                     Medium::height_fog(Vec3::zero(), sigma_s, 0.0, 0.0, falloff)
+                                 }
+                                 None => Medium::homogeneous(Vec3::zero(), sigma_s, 0.0),
+                             }
+                         });
 
     let render_start_time: DateTime<Local> = Local::now();
     println!("[{}] Rendering '{}' scene with {} samples at {} x {} dimensions...",
@@ -70,6 +155,78 @@ fn main() {
              width,
              height);
 
+    let settings = Settings { width,
+                              height,
+                              samples,
+                              bounces,
+                              spectral };
+
+    match frames {
+        // frame 1 opens its shutter at time zero and each frame keeps it open
+        // for the given fraction of the frame duration
+        Some(frames) => {
+            for frame in frames {
+                let start_time = (frame - 1) as f32 / fps;
+                let end_time = start_time + shutter / fps;
+                let frame_camera = view(scene_camera.frame(start_time, end_time));
+                world.refit(start_time, end_time);
+
+                let pixels = render(&settings, &*frame_camera, &world, &light_source, fog.clone());
+                save(&pixels, width, height, &format!("render.{:04}", frame));
+            }
+        }
+        None => {
+            let camera = view(scene_camera);
+            let pixels = render(&settings, &*camera, &world, &light_source, fog);
+            save(&pixels, width, height, "render");
+        }
+    }
+
+    let render_end_time: DateTime<Local> = Local::now();
+    println!("[{}] Finished rendering in {}.",
+             render_end_time.format("%H:%M:%S"),
+             utils::format_time(rendering_time.elapsed()));
+}
+
+/// Report a malformed command line option and exit
+fn usage(name: &str, form: &str, value: &str) -> ! {
+    eprintln!("usage: {}{}, got '{}'", name, form, value);
+    process::exit(2);
+}
+
+/// Parse a frame range such as 12 or 1:48, where frames are numbered from one
+fn parse_frames(range: &str) -> Option<RangeInclusive<u32>> {
+    let (first, last) = range.split_once(':').unwrap_or((range, range));
+    let first = first.parse::<u32>().ok()?;
+    let last = last.parse::<u32>().ok()?;
+    if first == 0 || first > last {
+        return None;
+    }
+    Some(first..=last)
+}
+
+/// The image and integrator settings shared by every frame
+struct Settings {
+    width: usize,
+    height: usize,
+    samples: u32,
+    bounces: u32,
+    spectral: bool,
+}
+
+/// Render one frame of the scene as seen from the camera
+fn render(settings: &Settings,
+          camera: &dyn Camera,
+          world: &bvh::BVH,
+          light_source: &plane::Plane,
+          fog: Option<Medium>)
+          -> Vec<f32> {
+    let Settings { width,
+                   height,
+                   samples,
+                   bounces,
+                   spectral } = *settings;
+
     let mut progress_bar = ProgressBar::new((width * height) as u64);
     progress_bar.show_speed = false;
 
@@ -85,6 +242,15 @@ fn main() {
         }
     });
 
+    let placement = camera.placement();
+    let camera_media = integrator::media_at(world, placement.origin, placement.start_time);
+    let mut environment = integrator::Environment::new(placement.atmosphere, camera_media);
+    if let Some(fog) = fog {
+        environment = environment.with_fog(fog);
+    }
+
+    let spread = camera.spread_angle(width, height);
+
     let mut pixels = vec![0.0f32; 3 * width * height];
     pixels.par_chunks_mut(3).enumerate().for_each(|(i, pixel)| {
         let mut color = Vec3::zero();
@@ -97,16 +263,26 @@ fn main() {
         (0..samples).for_each(|_| {
             let u = (x as f32 + rand::random::<f32>()) / width as f32;
             let v = (y as f32 + rand::random::<f32>()) / height as f32;
-            let ray = camera.get_ray(u, v, &mut rng);
-            color += utils::de_nan(&integrator::render_path_integrator(ray,
-                                                        &world,
-                                                        bounces,
-                                                        &light_source,
-                                                        camera.atmosphere,
-                                                        &mut rng));
+            let sample = match camera.generate_ray(u, v, &mut rng) {
+                Some(sample) => sample,
+                None => return,
+            };
+            let wavelengths = if spectral {
+                Some(spectrum::Wavelengths::sample(&mut rng))
+            } else {
+                None
+            };
+            let radiance = integrator::render_path_integrator(sample.ray.with_cone(0.0, spread),
+                                                              world,
+                                                              bounces,
+                                                              light_source,
+                                                              &environment,
+                                                              wavelengths,
+                                                              &mut rng);
+            color += sample.weight * utils::de_nan(&radiance);
         });
 
-        color /= samples as f32;
+        color *= camera.exposure() / samples as f32;
 
         pixel[0] = color.x();
         pixel[1] = color.y();
@@ -115,14 +291,22 @@ fn main() {
         atomic_counter.fetch_add(1, Ordering::SeqCst);
     });
 
-    let render_end_time: DateTime<Local> = Local::now();
-    println!("[{}] Finished rendering in {}. Render saved to render.hdr.",
-             render_end_time.format("%H:%M:%S"),
-             utils::format_time(rendering_time.elapsed()));
+    pixels
+}
+
+/// Write the pixels to name.hdr, along with denoised_name.hdr when denoising
+///
+/// Frames are written as Radiance HDR rather than OpenEXR because neither
+/// image crate in use can encode EXR; both formats keep the unclamped radiance.
+fn save(pixels: &[f32], width: usize, height: usize, name: &str) {
+    let buffer: ImageBuf<f32, Rgb> = ImageBuf::new_from(width, height, pixels.to_vec());
 
-    let buffer: ImageBuf<f32, Rgb> = ImageBuf::new_from(width, height, pixels.clone());
+    image2::io::write(format!("{}.hdr", name), &buffer).unwrap();
 
-    image2::io::write("render.hdr", &buffer).unwrap();
+    let render_end_time: DateTime<Local> = Local::now();
+    println!("[{}] Render saved to {}.hdr.",
+             render_end_time.format("%H:%M:%S"),
+             name);
 
     #[cfg(feature = "denoise")]
     {
@@ -131,15 +315,16 @@ fn main() {
         println!("[{}] Denoising image...",
                  denoise_start_time.format("%H:%M:%S"));
 
-        let denoised_output = denoise(&pixels, width, height);
+        let denoised_output = denoise(&pixels.to_vec(), width, height);
 
         let denoise_end_time: DateTime<Local> = Local::now();
-        println!("[{}] Finished denoising in {}. Render saved to denoised_render.hdr.",
+        println!("[{}] Finished denoising in {}. Render saved to denoised_{}.hdr.",
                  denoise_end_time.format("%H:%M:%S"),
-                 utils::format_time(denoising_time.elapsed()));
+                 utils::format_time(denoising_time.elapsed()),
+                 name);
 
         let denoised_buffer: ImageBuf<f32, Rgb> = ImageBuf::new_from(width, height, denoised_output);
 
-        image2::io::write("denoised_render.hdr", &denoised_buffer).unwrap();
+        image2::io::write(format!("denoised_{}.hdr", name), &denoised_buffer).unwrap();
     }
 }
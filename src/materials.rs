@@ -3,19 +3,29 @@ use std::sync::Arc;
 
 use glam::Vec3;
 use rand::rngs::ThreadRng;
+use rand::Rng;
 
 use basis::OrthonormalBasis;
 use hitable::HitRecord;
 use integrator::pick_sphere_point;
+use medium::Medium;
 use pdf::PDF;
 use ray::Ray;
-use texture::Texture;
+use texture::{ConstantTexture, Texture};
+use utils;
 
 pub struct ScatterRecord<'a> {
     pub specular_ray: Ray,
     pub attenuation: Vec3,
     pub pdf: PDF<'a>,
     pub specular: bool,
+    /// The material that produced the record when it differs from the hit's material
+    ///
+    /// Layered materials scatter from one of their components and the rest
+    /// of the path must evaluate that same component.
+    pub scatterer: Option<&'a dyn Material>,
+    /// The shading normal the scatterer saw when a wrapping material perturbed it
+    pub shading_normal: Option<Vec3>,
 }
 
 impl<'a> ScatterRecord<'a> {
@@ -27,7 +37,23 @@ impl<'a> ScatterRecord<'a> {
         ScatterRecord { specular_ray,
                         attenuation,
                         pdf,
-                        specular }
+                        specular,
+                        scatterer: None,
+                        shading_normal: None }
+    }
+
+    /// Record the shading normal that a wrapping material scattered the wrapped one with
+    ///
+    /// The wrapped material becomes the scatterer unless a layer inside it
+    /// already chose one, and the innermost perturbation is kept, so that the
+    /// integrator evaluates the scatterer's PDF with the normal it sampled.
+    pub fn with_shading_normal(mut self,
+                               material: &'a dyn Material,
+                               shading_normal: Vec3)
+                               -> ScatterRecord<'a> {
+        self.scatterer = self.scatterer.or(Some(material));
+        self.shading_normal = self.shading_normal.or(Some(shading_normal));
+        self
     }
 }
 
@@ -48,6 +74,36 @@ pub trait Material: Send + Sync {
     fn scattering_pdf(&self, _ray: &Ray, _record: &HitRecord, _scattered: &Ray) -> f32 {
         1.0
     }
+
+    /// Whether the scattered direction depends on the wavelength of the ray
+    fn dispersive(&self) -> bool {
+        false
+    }
+
+    /// The probability that a ray hitting the surface is stopped by it
+    ///
+    /// Surfaces with an opacity below one are skipped during intersection
+    /// so that cutouts such as leaves and fences can be built from textures.
+    fn opacity(&self, _record: &HitRecord) -> f32 {
+        1.0
+    }
+
+    /// Whether rays pass through the surface unchanged
+    ///
+    /// Shadow rays continue through such surfaces, which are used to bound
+    /// participating media.
+    fn transparent(&self) -> bool {
+        false
+    }
+
+    /// The medium filling the inside of the closed surface, if any
+    ///
+    /// The integrator switches to this medium when a path crosses the
+    /// surface against its geometric normal and back out when it crosses
+    /// along the normal.
+    fn interior(&self) -> Option<Arc<Medium>> {
+        None
+    }
 }
 
 #[derive(Clone)]
@@ -67,8 +123,7 @@ impl Material for Empty {
 #[derive(Clone)]
 pub struct Diffuse {
     pub albedo: Arc<dyn Texture>,
-    alpha: f32,
-    beta: f32,
+    pub sigma: Arc<dyn Texture>,
 }
 
 impl Diffuse {
@@ -77,16 +132,44 @@ impl Diffuse {
     /// albedo is a Vec3 of the RGB values assigned to the material
     /// where each value is a float between 0.0 and 1.0.
     pub fn new<T: Texture + 'static>(albedo: T, sigma: f32) -> Diffuse {
+        Diffuse::textured(albedo, ConstantTexture::splat(sigma))
+    }
+
+    /// Create a new Diffuse material whose roughness is read from a texture
+    ///
+    /// The first channel of the sigma texture is the Oren-Nayar roughness.
+    pub fn textured<T: Texture + 'static, S: Texture + 'static>(albedo: T, sigma: S) -> Diffuse {
         let albedo = Arc::new(albedo);
+        let sigma = Arc::new(sigma);
+        Diffuse { albedo, sigma }
+    }
+}
 
-        let constant = PI + sigma * (3.0 * PI - 4.0) / 6.0;
-        let alpha = 1.0 / constant;
-        let beta = sigma / constant;
+/// Evaluate the improved Oren-Nayar model for the given roughness
+///
+/// This method uses the improved Oren-Nayar model as implemented in Cycles:
+///
+/// Yasuhiro Fujii: A tiny improvement of Oren-Nayar reflectance model
+/// https://mimosa-pudica.net/improved-oren-nayar.html
+///
+/// https://developer.blender.org/diffusion/C/browse/master/src/kernel/closure/bsdf_oren_nayar.h
+fn oren_nayar(sigma: f32, wo: &Ray, record: &HitRecord, wi: &Ray) -> f32 {
+    let constant = PI + sigma * (3.0 * PI - 4.0) / 6.0;
+    let alpha = 1.0 / constant;
+    let beta = sigma / constant;
 
-        Diffuse { albedo,
-                  alpha,
-                  beta }
-    }
+    let l = wi.direction;
+    let v = wo.direction;
+    let n = record.shading_normal;
+
+    let nl = n.dot(l).max(0.0);
+    let nv = n.dot(v).max(0.0);
+    let lv = l.dot(v);
+
+    let s = lv - nl * nv;
+    let t = if s > 0.0 { nl.max(nv) } else { 1.0 };
+
+    nl * (alpha + beta * s / t)
 }
 
 impl Material for Diffuse {
@@ -97,32 +180,15 @@ impl Material for Diffuse {
                _rng: &mut ThreadRng)
                -> Option<ScatterRecord> {
         let scattered = Ray::new(record.point, ray.direction, ray.time);
-        let attenuation = self.albedo.value(record.u, record.v, &record.point);
+        let attenuation = self.albedo.value(record.u, record.v, &record.point, record.footprint);
         let pdf = PDF::CosinePDF { uvw: OrthonormalBasis::new(&record.shading_normal) };
         Some(ScatterRecord::new(scattered, attenuation, pdf, false))
     }
 
     /// Reflect light according to the Oren-Nayar model
-    ///
-    /// This method uses the improved Oren-Nayar model as implemented in Cycles:
-    ///
-    /// Yasuhiro Fujii: A tiny improvement of Oren-Nayar reflectance model
-    /// https://mimosa-pudica.net/improved-oren-nayar.html
-    ///
-    /// https://developer.blender.org/diffusion/C/browse/master/src/kernel/closure/bsdf_oren_nayar.h
     fn scattering_pdf(&self, wo: &Ray, record: &HitRecord, wi: &Ray) -> f32 {
-        let l = wi.direction;
-        let v = wo.direction;
-        let n = record.shading_normal;
-
-        let nl = n.dot(l).max(0.0);
-        let nv = n.dot(v).max(0.0);
-        let lv = l.dot(v);
-
-        let s = lv - nl * nv;
-        let t = if s > 0.0 { nl.max(nv) } else { 1.0 };
-
-        nl * (self.alpha + self.beta * s / t)
+        let sigma = self.sigma.scalar(record.u, record.v, &record.point, record.footprint);
+        oren_nayar(sigma, wo, record, wi)
     }
 }
 
@@ -173,8 +239,8 @@ fn schlick(cosine: f32, reference_index: f32) -> f32 {
 
 #[derive(Clone)]
 pub struct Reflective {
-    pub albedo: Vec3,
-    pub fuzz: f32,
+    pub albedo: Arc<dyn Texture>,
+    pub fuzz: Arc<dyn Texture>,
 }
 
 impl Reflective {
@@ -185,8 +251,15 @@ impl Reflective {
     /// for the fuzziness of the reflections due to the size of the sphere.
     /// Generally, the larger the sphere, the fuzzier the reflections will be.
     pub fn new(albedo: Vec3, fuzz: f32) -> Reflective {
-        Reflective { albedo: albedo,
-                     fuzz: fuzz }
+        Reflective::textured(ConstantTexture::new(albedo.x(), albedo.y(), albedo.z()),
+                             ConstantTexture::splat(fuzz))
+    }
+
+    /// Create a new Reflective material whose albedo and fuzz are read from textures
+    pub fn textured<T: Texture + 'static, S: Texture + 'static>(albedo: T, fuzz: S) -> Reflective {
+        let albedo = Arc::new(albedo);
+        let fuzz = Arc::new(fuzz);
+        Reflective { albedo, fuzz }
     }
 }
 
@@ -201,17 +274,50 @@ impl Material for Reflective {
     /// to determine the ray that is being reflected from the surface of the material.
     fn scatter(&self, ray: &Ray, record: &HitRecord, rng: &mut ThreadRng) -> Option<ScatterRecord> {
         let reflected: Vec3 = reflect(ray.direction, record.shading_normal);
+        let fuzz = self.fuzz.scalar(record.u, record.v, &record.point, record.footprint);
+        let albedo = self.albedo.value(record.u, record.v, &record.point, record.footprint);
         let specular_ray = Ray::new(record.point,
-                                    reflected + self.fuzz * pick_sphere_point(rng),
+                                    reflected + fuzz * pick_sphere_point(rng),
                                     ray.time);
         let pdf = PDF::CosinePDF { uvw: OrthonormalBasis::new(&record.shading_normal) };
-        Some(ScatterRecord::new(specular_ray, self.albedo, pdf, true))
+        Some(ScatterRecord::new(specular_ray, albedo, pdf, true))
+    }
+}
+
+/// Dispersion describes how the index of refraction varies with wavelength
+#[derive(Clone)]
+pub enum Dispersion {
+    None,
+    /// Cauchy's equation n = a + b / λ² with λ in micrometers
+    Cauchy { a: f32, b: f32 },
+    /// The Sellmeier equation with coefficients b and c (in μm²)
+    Sellmeier { b: [f32; 3], c: [f32; 3] },
+}
+
+impl Dispersion {
+    /// Compute the index of refraction at the given wavelength in nanometers
+    pub fn index(&self, wavelength: f32) -> Option<f32> {
+        let micrometers = wavelength / 1000.0;
+        let squared = micrometers * micrometers;
+
+        match self {
+            Dispersion::None => None,
+            Dispersion::Cauchy { a, b } => Some(a + b / squared),
+            Dispersion::Sellmeier { b, c } => {
+                let sum: f32 = (0..3).map(|i| b[i] * squared / (squared - c[i])).sum();
+                Some((1.0 + sum).sqrt())
+            }
+        }
     }
 }
 
+/// The Fraunhofer d line at which indices of refraction are usually quoted
+const SODIUM_D_LINE: f32 = 587.6;
+
 #[derive(Clone)]
 pub struct Refractive {
-    pub refractive_index: f32,
+    pub refractive_index: Arc<dyn Texture>,
+    pub dispersion: Dispersion,
 }
 
 impl Refractive {
@@ -223,7 +329,46 @@ impl Refractive {
     /// fuzz accounts for the fuzziness of the reflections due to the size of the sphere.
     /// Generally, the larger the sphere, the fuzzier the reflections will be.
     pub fn new(index: f32) -> Refractive {
-        Refractive { refractive_index: index }
+        Refractive::textured(ConstantTexture::splat(index))
+    }
+
+    /// Create a new Refractive material whose index of refraction is read from a texture
+    pub fn textured<T: Texture + 'static>(index: T) -> Refractive {
+        Refractive { refractive_index: Arc::new(index),
+                     dispersion: Dispersion::None }
+    }
+
+    /// Create a dispersive Refractive material following Cauchy's equation
+    ///
+    /// a and b are the first two Cauchy coefficients with b in μm².
+    /// For example, BK7 glass is approximately a = 1.5046 and b = 0.0042.
+    pub fn cauchy(a: f32, b: f32) -> Refractive {
+        let dispersion = Dispersion::Cauchy { a, b };
+        let index = ConstantTexture::splat(dispersion.index(SODIUM_D_LINE).unwrap());
+        Refractive { refractive_index: Arc::new(index),
+                     dispersion }
+    }
+
+    /// Create a dispersive Refractive material following the Sellmeier equation
+    ///
+    /// b and c are the three pairs of Sellmeier coefficients with c in μm²
+    /// as published in glass catalogs and https://refractiveindex.info.
+    pub fn sellmeier(b: [f32; 3], c: [f32; 3]) -> Refractive {
+        let dispersion = Dispersion::Sellmeier { b, c };
+        let index = ConstantTexture::splat(dispersion.index(SODIUM_D_LINE).unwrap());
+        Refractive { refractive_index: Arc::new(index),
+                     dispersion }
+    }
+
+    /// Find the index of refraction for the wavelength carried by the ray
+    ///
+    /// Rays traced in RGB mode carry no wavelength and use the index at the d line.
+    pub fn index(&self, ray: &Ray, record: &HitRecord) -> f32 {
+        ray.wavelength
+           .and_then(|wavelength| self.dispersion.index(wavelength))
+           .unwrap_or_else(|| {
+               self.refractive_index.scalar(record.u, record.v, &record.point, record.footprint)
+           })
     }
 }
 
@@ -246,22 +391,23 @@ impl Material for Refractive {
                _rng: &mut ThreadRng)
                -> Option<ScatterRecord> {
         let reflected: Vec3 = reflect(ray.direction, record.shading_normal);
+        let index = self.index(ray, record);
         let incident: f32 = ray.direction.dot(record.shading_normal);
 
         let (outward_normal, refractive_index, cosine) = if incident > 0.0 {
             (-record.shading_normal,
-             self.refractive_index,
-             self.refractive_index * ray.direction.dot(record.shading_normal)
+             index,
+             index * ray.direction.dot(record.shading_normal)
              / ray.direction.length())
         } else {
             (record.shading_normal,
-             1.0 / self.refractive_index,
+             1.0 / index,
              -ray.direction.dot(record.shading_normal) / ray.direction.length())
         };
 
         let refracted = refract(ray.direction, outward_normal, refractive_index);
         let reflect_probability = match refracted {
-            Some(_) => schlick(cosine, self.refractive_index),
+            Some(_) => schlick(cosine, index),
             None => 1.0,
         };
 
@@ -276,17 +422,95 @@ impl Material for Refractive {
             Some(ScatterRecord::new(specular_ray, attenuation, pdf, true))
         }
     }
+
+    fn dispersive(&self) -> bool {
+        !matches!(self.dispersion, Dispersion::None)
+    }
+}
+
+#[derive(Clone)]
+pub struct Subsurface {
+    pub surface: Refractive,
+    pub medium: Arc<Medium>,
+}
+
+impl Subsurface {
+    /// Create a translucent material that scatters light beneath its surface
+    ///
+    /// Light refracts through a smooth dielectric boundary and performs a
+    /// random walk through a medium inside the closed surface, which must
+    /// have outward facing normals. The albedo is the color of the material
+    /// after many scattering events and the mean free path is the average
+    /// distance light travels in each channel between events, so a longer
+    /// red path gives skin its red glow.
+    pub fn new(albedo: Vec3, mean_free_path: Vec3, index: f32) -> Subsurface {
+        Subsurface::with_anisotropy(albedo, mean_free_path, index, 0.0)
+    }
+
+    /// Create a subsurface material with a Henyey-Greenstein phase function
+    pub fn with_anisotropy(albedo: Vec3, mean_free_path: Vec3, index: f32, g: f32) -> Subsurface {
+        let sigma_t = Vec3::one() / mean_free_path.max(Vec3::splat(1e-6));
+        let single_scattering = Vec3::new(single_scattering_albedo(albedo.x()),
+                                          single_scattering_albedo(albedo.y()),
+                                          single_scattering_albedo(albedo.z()));
+        let sigma_s = single_scattering * sigma_t;
+
+        Subsurface { surface: Refractive::new(index),
+                     medium: Arc::new(Medium::homogeneous(sigma_t - sigma_s, sigma_s, g)) }
+    }
+}
+
+/// Invert the multiple scattering albedo of a random walk to the single
+/// scattering albedo of the medium
+///
+/// Reference:
+/// Matt Jen-Yuan Chiang, Peter Kutz, Brent Burley
+/// Practical and Controllable Subsurface Scattering for Production Path Tracing
+/// ACM SIGGRAPH 2016 Talks
+fn single_scattering_albedo(albedo: f32) -> f32 {
+    let albedo = utils::clamp(albedo, 0.0, 0.999);
+    let root = 4.09712 + 4.20863 * albedo
+               - (9.59217 + 41.6808 * albedo + 17.7126 * albedo * albedo).sqrt();
+    1.0 - root * root
+}
+
+impl Material for Subsurface {
+    /// Refract or reflect at the smooth dielectric boundary
+    ///
+    /// Paths entering and leaving the surface both see the dielectric, so
+    /// paths reaching the surface from inside are reflected back into the
+    /// medium with the Fresnel reflectance or by total internal reflection.
+    fn scatter(&self, ray: &Ray, record: &HitRecord, rng: &mut ThreadRng) -> Option<ScatterRecord> {
+        self.surface.scatter(ray, record, rng)
+    }
+
+    fn dispersive(&self) -> bool {
+        self.surface.dispersive()
+    }
+
+    fn interior(&self) -> Option<Arc<Medium>> {
+        Some(self.medium.clone())
+    }
 }
 
 #[derive(Clone)]
 pub struct Light {
     pub emit: Arc<dyn Texture>,
+    pub strength: Arc<dyn Texture>,
 }
 
 impl Light {
     pub fn new<T: Texture + 'static>(emit: T) -> Light {
+        Light::with_strength(emit, ConstantTexture::splat(1.0))
+    }
+
+    /// Create a new Light whose emitted color is scaled by a strength texture
+    pub fn with_strength<T: Texture + 'static, S: Texture + 'static>(emit: T,
+                                                                     strength: S)
+                                                                     -> Light {
         let emit = Arc::new(emit);
-        Light { emit: emit }
+        let strength = Arc::new(strength);
+        Light { emit, strength }
     }
 }
 
@@ -301,7 +525,8 @@ impl Material for Light {
 
     fn emitted(&self, ray: &Ray, hit: &HitRecord) -> Vec3 {
         if hit.shading_normal.dot(ray.direction) < 0.0 {
-            self.emit.value(hit.u, hit.v, &hit.point)
+            let strength = self.strength.scalar(hit.u, hit.v, &hit.point, hit.footprint);
+            strength * self.emit.value(hit.u, hit.v, &hit.point, hit.footprint)
         } else {
             Vec3::zero()
         }
@@ -309,22 +534,704 @@ impl Material for Light {
 }
 
 #[derive(Clone)]
-pub struct Isotropic {
-    pub albedo: Arc<dyn Texture>,
+pub struct Principled {
+    pub base_color: Arc<dyn Texture>,
+    pub roughness: Arc<dyn Texture>,
+    pub metalness: Arc<dyn Texture>,
 }
 
-impl Isotropic {
-    pub fn new<T: Texture + 'static>(albedo: T) -> Isotropic {
-        let albedo = Arc::new(albedo);
-        Isotropic { albedo }
+impl Principled {
+    /// Create a new Principled material following the metal-roughness workflow
+    ///
+    /// base_color is the albedo of dielectric surfaces and the reflectance of metals.
+    /// The first channels of roughness and metalness are read per hit so that
+    /// scanned PBR texture sets can be used directly. Packed maps can be split
+    /// into their channels with ChannelTexture.
+    pub fn new<T: Texture + 'static, R: Texture + 'static, M: Texture + 'static>(base_color: T,
+                                                                                 roughness: R,
+                                                                                 metalness: M)
+                                                                                 -> Principled {
+        Principled { base_color: Arc::new(base_color),
+                     roughness: Arc::new(roughness),
+                     metalness: Arc::new(metalness) }
     }
 }
 
-impl Material for Isotropic {
+impl Material for Principled {
+    /// Scatter from either the metallic or the diffuse lobe
+    ///
+    /// The lobe is chosen with probability equal to its weight, so the
+    /// weights cancel and each lobe is evaluated as if it were alone.
     fn scatter(&self, ray: &Ray, record: &HitRecord, rng: &mut ThreadRng) -> Option<ScatterRecord> {
-        let scattered = Ray::new(record.point, pick_sphere_point(rng), ray.time);
-        let attenuation = self.albedo.value(record.u, record.v, &record.point);
+        let base_color = self.base_color.value(record.u, record.v, &record.point, record.footprint);
+        let metalness = self.metalness.scalar(record.u, record.v, &record.point, record.footprint);
         let pdf = PDF::CosinePDF { uvw: OrthonormalBasis::new(&record.shading_normal) };
-        Some(ScatterRecord::new(scattered, attenuation, pdf, true))
+
+        if rng.gen::<f32>() < metalness {
+            let roughness =
+                self.roughness.scalar(record.u, record.v, &record.point, record.footprint);
+            let reflected = reflect(ray.direction, record.shading_normal);
+            let specular_ray = Ray::new(record.point,
+                                        reflected + roughness * pick_sphere_point(rng),
+                                        ray.time);
+            Some(ScatterRecord::new(specular_ray, base_color, pdf, true))
+        } else {
+            let scattered = Ray::new(record.point, ray.direction, ray.time);
+            Some(ScatterRecord::new(scattered, base_color, pdf, false))
+        }
+    }
+
+    fn scattering_pdf(&self, wo: &Ray, record: &HitRecord, wi: &Ray) -> f32 {
+        let roughness = self.roughness.scalar(record.u, record.v, &record.point, record.footprint);
+        oren_nayar(roughness, wo, record, wi)
+    }
+}
+
+#[derive(Clone)]
+pub struct Transparent {}
+
+impl Transparent {
+    /// Create an invisible surface that lets rays pass through unchanged
+    ///
+    /// This is used for the boundaries of participating media.
+    pub fn new() -> Transparent {
+        Transparent {}
+    }
+}
+
+impl Material for Transparent {
+    fn scatter(&self, ray: &Ray, record: &HitRecord, _rng: &mut ThreadRng) -> Option<ScatterRecord> {
+        let passed = Ray::new(record.point, ray.direction, ray.time);
+        let pdf = PDF::CosinePDF { uvw: OrthonormalBasis::new(&record.shading_normal) };
+        Some(ScatterRecord::new(passed, Vec3::one(), pdf, true))
+    }
+
+    fn transparent(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Clone)]
+pub struct Interior {
+    pub material: Arc<dyn Material>,
+    pub medium: Arc<Medium>,
+}
+
+impl Interior {
+    /// Fill the inside of a closed surface with a participating medium
+    ///
+    /// The surface keeps scattering light with its own material, so glass
+    /// with a colored medium inside or skin over a scattering interior can
+    /// be built from an existing material and a Medium.
+    pub fn new<M: Material + 'static>(material: M, medium: Medium) -> Interior {
+        Interior { material: Arc::new(material),
+                   medium: Arc::new(medium) }
+    }
+}
+
+impl Material for Interior {
+    fn scatter(&self, ray: &Ray, record: &HitRecord, rng: &mut ThreadRng) -> Option<ScatterRecord> {
+        self.material.scatter(ray, record, rng)
+    }
+
+    fn emitted(&self, ray: &Ray, hit: &HitRecord) -> Vec3 {
+        self.material.emitted(ray, hit)
+    }
+
+    fn scattering_pdf(&self, ray: &Ray, record: &HitRecord, scattered: &Ray) -> f32 {
+        self.material.scattering_pdf(ray, record, scattered)
+    }
+
+    fn dispersive(&self) -> bool {
+        self.material.dispersive()
+    }
+
+    fn transparent(&self) -> bool {
+        self.material.transparent()
+    }
+
+    fn opacity(&self, record: &HitRecord) -> f32 {
+        self.material.opacity(record)
+    }
+
+    fn interior(&self) -> Option<Arc<Medium>> {
+        Some(self.medium.clone())
+    }
+}
+
+/// Build an orthonormal tangent frame at the hit point
+///
+/// The tangent is dpdu projected onto the plane of the shading normal.
+/// The bitangent follows the orientation of dpdv so that mirrored texture
+/// coordinates keep their handedness. Hits without surface derivatives
+/// fall back to an arbitrary frame around the shading normal.
+fn tangent_frame(record: &HitRecord) -> (Vec3, Vec3, Vec3) {
+    let normal = record.shading_normal;
+    let projected = record.dpdu - normal * normal.dot(record.dpdu);
+
+    if projected.length_squared() < 1e-12 {
+        let frame = OrthonormalBasis::new(&normal);
+        return (frame.u(), frame.v(), frame.w());
+    }
+
+    let tangent = projected.normalize();
+    let mut bitangent = normal.cross(tangent);
+    if bitangent.dot(record.dpdv) < 0.0 {
+        bitangent = -bitangent;
+    }
+
+    (tangent, bitangent, normal)
+}
+
+#[derive(Clone)]
+pub struct NormalMap {
+    pub material: Arc<dyn Material>,
+    pub map: Arc<dyn Texture>,
+}
+
+impl NormalMap {
+    /// Perturb the shading normal of a material with a tangent-space normal map
+    ///
+    /// The map stores normals encoded as colors where each channel in [0, 1]
+    /// maps to a component in [-1, 1] and blue points along the surface normal.
+    /// Normal maps hold data rather than color and should be loaded linearly.
+    pub fn new<M: Material + 'static, T: Texture + 'static>(material: M, map: T) -> NormalMap {
+        let material = Arc::new(material);
+        let map = Arc::new(map);
+        NormalMap { material, map }
+    }
+
+    fn perturb(&self, record: &HitRecord) -> HitRecord {
+        let (tangent, bitangent, normal) = tangent_frame(record);
+        let encoded = self.map.value(record.u, record.v, &record.point, record.footprint);
+        let local = 2.0 * encoded - Vec3::one();
+
+        let mut perturbed = record.clone();
+        perturbed.shading_normal =
+            (local.x() * tangent + local.y() * bitangent + local.z() * normal).normalize();
+        perturbed
+    }
+}
+
+impl Material for NormalMap {
+    fn scatter(&self, ray: &Ray, record: &HitRecord, rng: &mut ThreadRng) -> Option<ScatterRecord> {
+        let perturbed = self.perturb(record);
+        let scatter_record = self.material.scatter(ray, &perturbed, rng)?;
+        Some(scatter_record.with_shading_normal(&*self.material, perturbed.shading_normal))
+    }
+
+    fn emitted(&self, ray: &Ray, hit: &HitRecord) -> Vec3 {
+        self.material.emitted(ray, &self.perturb(hit))
+    }
+
+    fn scattering_pdf(&self, ray: &Ray, record: &HitRecord, scattered: &Ray) -> f32 {
+        self.material.scattering_pdf(ray, &self.perturb(record), scattered)
+    }
+
+    fn dispersive(&self) -> bool {
+        self.material.dispersive()
+    }
+
+    fn transparent(&self) -> bool {
+        self.material.transparent()
+    }
+
+    fn opacity(&self, record: &HitRecord) -> f32 {
+        self.material.opacity(record)
+    }
+    fn interior(&self) -> Option<Arc<Medium>> {
+        self.material.interior()
+    }
+}
+
+#[derive(Clone)]
+pub struct BumpMap {
+    pub material: Arc<dyn Material>,
+    pub map: Arc<dyn Texture>,
+    pub scale: f32,
+}
+
+impl BumpMap {
+    /// Perturb the shading normal of a material with a scalar height map
+    ///
+    /// The first channel of the map is the displacement along the normal
+    /// and scale converts it to world units.
+    pub fn new<M: Material + 'static, T: Texture + 'static>(material: M,
+                                                            map: T,
+                                                            scale: f32)
+                                                            -> BumpMap {
+        let material = Arc::new(material);
+        let map = Arc::new(map);
+        BumpMap { material,
+                  map,
+                  scale }
+    }
+
+    /// Displace the shading normal by the gradient of the height map
+    ///
+    /// The gradient is found with forward differences in texture space and
+    /// the derivative of the normal itself is ignored as it is usually small.
+    ///
+    /// See Section 9.3 in Physically Based Rendering, Third Edition.
+    fn perturb(&self, record: &HitRecord) -> HitRecord {
+        let delta = 0.0005;
+        let (tangent, bitangent, normal) = tangent_frame(record);
+
+        let (dpdu, dpdv) = if record.dpdu.length_squared() > 0.0 {
+            (record.dpdu, record.dpdv)
+        } else {
+            (tangent, bitangent)
+        };
+
+        // solid textures read the point, so it moves along with the texture coordinates
+        let height = |u: f32, v: f32, point: Vec3| {
+            self.scale * self.map.value(u, v, &point, record.footprint).x()
+        };
+        let displacement = height(record.u, record.v, record.point);
+        let u_displacement = height(record.u + delta, record.v, record.point + delta * dpdu);
+        let v_displacement = height(record.u, record.v + delta, record.point + delta * dpdv);
+
+        let bumped_dpdu = dpdu + (u_displacement - displacement) / delta * normal;
+        let bumped_dpdv = dpdv + (v_displacement - displacement) / delta * normal;
+
+        let mut bumped_normal = bumped_dpdu.cross(bumped_dpdv).normalize();
+        if bumped_normal.dot(normal) < 0.0 {
+            bumped_normal = -bumped_normal;
+        }
+
+        let mut perturbed = record.clone();
+        perturbed.shading_normal = bumped_normal;
+        perturbed
+    }
+}
+
+impl Material for BumpMap {
+    fn scatter(&self, ray: &Ray, record: &HitRecord, rng: &mut ThreadRng) -> Option<ScatterRecord> {
+        let perturbed = self.perturb(record);
+        let scatter_record = self.material.scatter(ray, &perturbed, rng)?;
+        Some(scatter_record.with_shading_normal(&*self.material, perturbed.shading_normal))
+    }
+
+    fn emitted(&self, ray: &Ray, hit: &HitRecord) -> Vec3 {
+        self.material.emitted(ray, &self.perturb(hit))
+    }
+
+    fn scattering_pdf(&self, ray: &Ray, record: &HitRecord, scattered: &Ray) -> f32 {
+        self.material.scattering_pdf(ray, &self.perturb(record), scattered)
+    }
+
+    fn dispersive(&self) -> bool {
+        self.material.dispersive()
+    }
+
+    fn transparent(&self) -> bool {
+        self.material.transparent()
+    }
+
+    fn opacity(&self, record: &HitRecord) -> f32 {
+        self.material.opacity(record)
+    }
+    fn interior(&self) -> Option<Arc<Medium>> {
+        self.material.interior()
+    }
+}
+
+#[derive(Clone)]
+pub struct AlphaMask {
+    pub material: Arc<dyn Material>,
+    pub opacity: Arc<dyn Texture>,
+}
+
+impl AlphaMask {
+    /// Cut parts of a surface away with an opacity texture
+    ///
+    /// The first channel of the texture is the opacity where zero is
+    /// fully transparent and one is fully opaque. Fractional opacities
+    /// are resolved stochastically during intersection.
+    pub fn new<M: Material + 'static, T: Texture + 'static>(material: M, opacity: T) -> AlphaMask {
+        let material = Arc::new(material);
+        let opacity = Arc::new(opacity);
+        AlphaMask { material, opacity }
+    }
+}
+
+impl Material for AlphaMask {
+    fn scatter(&self, ray: &Ray, record: &HitRecord, rng: &mut ThreadRng) -> Option<ScatterRecord> {
+        self.material.scatter(ray, record, rng)
+    }
+
+    fn emitted(&self, ray: &Ray, hit: &HitRecord) -> Vec3 {
+        self.material.emitted(ray, hit)
+    }
+
+    fn scattering_pdf(&self, ray: &Ray, record: &HitRecord, scattered: &Ray) -> f32 {
+        self.material.scattering_pdf(ray, record, scattered)
+    }
+
+    fn dispersive(&self) -> bool {
+        self.material.dispersive()
+    }
+
+    fn transparent(&self) -> bool {
+        self.material.transparent()
+    }
+
+    fn opacity(&self, record: &HitRecord) -> f32 {
+        let opacity = self.opacity.scalar(record.u, record.v, &record.point, record.footprint);
+        opacity * self.material.opacity(record)
+    }
+
+    fn interior(&self) -> Option<Arc<Medium>> {
+        self.material.interior()
+    }
+}
+
+#[derive(Clone)]
+pub struct MixMaterial {
+    pub first: Arc<dyn Material>,
+    pub second: Arc<dyn Material>,
+    pub weight: Arc<dyn Texture>,
+}
+
+impl MixMaterial {
+    /// Blend two materials by a constant weight
+    ///
+    /// A weight of zero gives the first material and a weight of one the second.
+    pub fn new<M: Material + 'static, N: Material + 'static>(first: M,
+                                                             second: N,
+                                                             weight: f32)
+                                                             -> MixMaterial {
+        MixMaterial::textured(first, second, ConstantTexture::splat(weight))
+    }
+
+    /// Blend two materials by a weight read from the first channel of a texture
+    pub fn textured<M: Material + 'static, N: Material + 'static, T: Texture + 'static>(
+        first: M,
+        second: N,
+        weight: T)
+        -> MixMaterial {
+        MixMaterial { first: Arc::new(first),
+                      second: Arc::new(second),
+                      weight: Arc::new(weight) }
+    }
+
+    fn weight(&self, record: &HitRecord) -> f32 {
+        let weight = self.weight.scalar(record.u, record.v, &record.point, record.footprint);
+        utils::clamp(weight, 0.0, 1.0)
+    }
+}
+
+impl Material for MixMaterial {
+    /// Scatter from one of the two materials
+    ///
+    /// The second material is chosen with probability equal to its weight.
+    /// The selection probability cancels the blend weight, so the chosen
+    /// material's attenuation and PDFs are used unscaled for the rest of the
+    /// bounce. The chosen material is recorded so that the integrator
+    /// evaluates its scattering PDF rather than the blend.
+    fn scatter(&self, ray: &Ray, record: &HitRecord, rng: &mut ThreadRng) -> Option<ScatterRecord> {
+        let chosen = if rng.gen::<f32>() < self.weight(record) {
+            &self.second
+        } else {
+            &self.first
+        };
+
+        let mut scatter_record = chosen.scatter(ray, record, rng)?;
+        scatter_record.scatterer = scatter_record.scatterer.or_else(|| Some(chosen.as_ref()));
+        Some(scatter_record)
+    }
+
+    fn emitted(&self, ray: &Ray, hit: &HitRecord) -> Vec3 {
+        let weight = self.weight(hit);
+        (1.0 - weight) * self.first.emitted(ray, hit) + weight * self.second.emitted(ray, hit)
+    }
+
+    /// Blend the scattering PDFs of both materials by the weight
+    fn scattering_pdf(&self, ray: &Ray, record: &HitRecord, scattered: &Ray) -> f32 {
+        let weight = self.weight(record);
+        (1.0 - weight) * self.first.scattering_pdf(ray, record, scattered)
+        + weight * self.second.scattering_pdf(ray, record, scattered)
+    }
+
+    fn dispersive(&self) -> bool {
+        self.first.dispersive() || self.second.dispersive()
+    }
+
+    fn transparent(&self) -> bool {
+        self.first.transparent() && self.second.transparent()
+    }
+
+    fn opacity(&self, record: &HitRecord) -> f32 {
+        let weight = self.weight(record);
+        (1.0 - weight) * self.first.opacity(record) + weight * self.second.opacity(record)
+    }
+
+    fn interior(&self) -> Option<Arc<Medium>> {
+        self.first.interior().or_else(|| self.second.interior())
+    }
+}
+
+#[derive(Clone)]
+pub struct AddMaterial {
+    pub material: Arc<dyn Material>,
+    pub emission: Arc<dyn Material>,
+}
+
+impl AddMaterial {
+    /// Layer the emission of one material on top of another material
+    ///
+    /// Light is scattered by the base material only while the emission of
+    /// both materials is summed. This makes glowing paint or hot metal
+    /// from an existing material and a Light.
+    pub fn new<M: Material + 'static, E: Material + 'static>(material: M,
+                                                             emission: E)
+                                                             -> AddMaterial {
+        AddMaterial { material: Arc::new(material),
+                      emission: Arc::new(emission) }
+    }
+}
+
+impl Material for AddMaterial {
+    fn scatter(&self, ray: &Ray, record: &HitRecord, rng: &mut ThreadRng) -> Option<ScatterRecord> {
+        self.material.scatter(ray, record, rng)
+    }
+
+    fn emitted(&self, ray: &Ray, hit: &HitRecord) -> Vec3 {
+        self.material.emitted(ray, hit) + self.emission.emitted(ray, hit)
+    }
+
+    fn scattering_pdf(&self, ray: &Ray, record: &HitRecord, scattered: &Ray) -> f32 {
+        self.material.scattering_pdf(ray, record, scattered)
+    }
+
+    fn dispersive(&self) -> bool {
+        self.material.dispersive()
+    }
+
+    fn transparent(&self) -> bool {
+        self.material.transparent()
+    }
+
+    fn opacity(&self, record: &HitRecord) -> f32 {
+        self.material.opacity(record)
+    }
+    fn interior(&self) -> Option<Arc<Medium>> {
+        self.material.interior()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bvh::BVH;
+    use hitable::{FlipNormals, Hitable};
+    use integrator::{render_path_integrator, Environment};
+    use plane::{Axis, Plane};
+    use rand::thread_rng;
+    use sphere::Sphere;
+    use triangle::Triangle;
+
+    /// A solid texture whose height rises along the x axis
+    struct Ramp;
+
+    impl Texture for Ramp {
+        fn value(&self, _u: f32, _v: f32, p: &Vec3, _footprint: f32) -> Vec3 {
+            Vec3::splat(p.x())
+        }
+    }
+
+    /// A texture that returns the u texture coordinate
+    struct Gradient;
+
+    impl Texture for Gradient {
+        fn value(&self, u: f32, _v: f32, _p: &Vec3, _footprint: f32) -> Vec3 {
+            Vec3::splat(u)
+        }
+    }
+
+    fn flat_hit() -> HitRecord {
+        let normal = Vec3::new(0.0, 0.0, 1.0);
+        HitRecord::new(1.0, 0.5, 0.5, Vec3::zero(), normal, normal, Arc::new(Empty::new()))
+            .with_derivatives(Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0))
+    }
+
+    #[test]
+    fn test_normal_map_follows_tangent_frame() {
+        let flat = NormalMap::new(Empty::new(), ConstantTexture::new(0.5, 0.5, 1.0));
+        let normal = flat.perturb(&flat_hit()).shading_normal;
+        assert!((normal - Vec3::new(0.0, 0.0, 1.0)).length() < 1e-4);
+
+        let tilted = NormalMap::new(Empty::new(), ConstantTexture::new(1.0, 0.5, 1.0));
+        let normal = tilted.perturb(&flat_hit()).shading_normal;
+        assert!((normal - Vec3::new(1.0, 0.0, 1.0).normalize()).length() < 1e-4);
+    }
+
+    #[test]
+    fn test_bump_map_slopes_with_solid_texture() {
+        let bump = BumpMap::new(Empty::new(), Ramp, 1.0);
+        let normal = bump.perturb(&flat_hit()).shading_normal;
+        assert!((normal - Vec3::new(-1.0, 0.0, 1.0).normalize()).length() < 1e-3);
+    }
+
+    #[test]
+    fn test_principled_reads_textured_parameters() {
+        let mut rng = thread_rng();
+        let ray = Ray::new(Vec3::new(-1.0, 0.0, 1.0), Vec3::new(1.0, 0.0, -1.0), 0.0);
+        let dielectric = HitRecord { u: 0.0, ..flat_hit() };
+        let metal = HitRecord { u: 1.0, ..flat_hit() };
+
+        // metalness picks the lobe and the metal's roughness of zero reflects like a mirror
+        let material =
+            Principled::new(ConstantTexture::splat(0.8), ConstantTexture::splat(0.0), Gradient);
+        for _ in 0..16 {
+            assert!(!material.scatter(&ray, &dielectric, &mut rng).unwrap().specular);
+
+            let record = material.scatter(&ray, &metal, &mut rng).unwrap();
+            assert!(record.specular);
+            let reflected = Vec3::new(1.0, 0.0, 1.0).normalize();
+            assert!((record.specular_ray.direction - reflected).length() < 1e-4);
+        }
+
+        // roughness drives the Oren-Nayar lobe at each hit
+        let material =
+            Principled::new(ConstantTexture::splat(0.8), Gradient, ConstantTexture::splat(0.0));
+        let scattered = Ray::new(Vec3::zero(), Vec3::new(0.3, 0.2, 1.0), 0.0);
+        for &record in &[&dielectric, &metal] {
+            let expected = oren_nayar(record.u, &ray, record, &scattered);
+            assert!((material.scattering_pdf(&ray, record, &scattered) - expected).abs() < 1e-6);
+        }
+        let smooth = material.scattering_pdf(&ray, &dielectric, &scattered);
+        let rough = material.scattering_pdf(&ray, &metal, &scattered);
+        assert!((smooth - rough).abs() > 1e-3);
+    }
+
+    #[test]
+    fn test_alpha_mask_lets_rays_through() {
+        let ray = Ray::new(Vec3::new(0.25, 0.25, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let normal = Vec3::new(0.0, 0.0, 1.0);
+
+        for &opacity in &[0.0, 1.0] {
+            let mask = || AlphaMask::new(Empty::new(), ConstantTexture::splat(opacity));
+            let shapes: Vec<Box<dyn Hitable>> =
+                vec![Box::new(Sphere::new(Vec3::zero(), Vec3::zero(), 1.0, mask(), 0.0, 1.0)),
+                     Box::new(Triangle::new(Vec3::new(0.0, 0.0, 0.0),
+                                            Vec3::new(1.0, 0.0, 0.0),
+                                            Vec3::new(0.0, 1.0, 0.0),
+                                            normal,
+                                            normal,
+                                            normal,
+                                            mask())),
+                     Box::new(Plane::new(Axis::XY, 0.0, 1.0, 0.0, 1.0, 0.0, mask()))];
+
+            for shape in &shapes {
+                assert_eq!(shape.hit(&ray, 1e-4, f32::MAX).is_some(), opacity > 0.0);
+            }
+        }
+
+        // half opaque surfaces stop half of the rays, and always the same ones
+        let mask = AlphaMask::new(Empty::new(), ConstantTexture::splat(0.5));
+        let plane = Plane::new(Axis::XY, 0.0, 1.0, 0.0, 1.0, 0.0, mask);
+        let samples = 10000;
+        let mut stopped = 0;
+        for i in 0..samples {
+            let offset = Vec3::new(i as f32 / samples as f32, 0.5, 5.0);
+            let ray = Ray::new(offset, Vec3::new(0.0, 0.0, -1.0), 0.0);
+            let hit = plane.hit(&ray, 1e-4, f32::MAX).is_some();
+            assert_eq!(plane.hit(&ray, 1e-4, f32::MAX).is_some(), hit);
+            stopped += hit as usize;
+        }
+        assert!((stopped as f32 / samples as f32 - 0.5).abs() < 0.03);
+    }
+
+    #[test]
+    fn test_mix_and_add_weight_their_materials() {
+        let mut rng = thread_rng();
+        let ray = Ray::new(Vec3::new(-1.0, 0.0, 1.0), Vec3::new(1.0, 0.0, -1.0), 0.0);
+        let record = flat_hit();
+        let scattered = Ray::new(Vec3::zero(), Vec3::new(0.3, 0.2, 1.0), 0.0);
+
+        // the second material is chosen with probability equal to the weight
+        let mix = MixMaterial::new(Diffuse::new(ConstantTexture::splat(0.5), 0.0),
+                                   Reflective::new(Vec3::one(), 0.0),
+                                   0.25);
+        let samples = 20000;
+        let specular = (0..samples)
+            .filter(|_| mix.scatter(&ray, &record, &mut rng).unwrap().specular)
+            .count();
+        assert!((specular as f32 / samples as f32 - 0.25).abs() < 0.02);
+
+        let pdf = mix.scattering_pdf(&ray, &record, &scattered);
+        let expected = 0.75 * mix.first.scattering_pdf(&ray, &record, &scattered)
+                       + 0.25 * mix.second.scattering_pdf(&ray, &record, &scattered);
+        assert!((pdf - expected).abs() < 1e-6);
+
+        // emission is blended by a mix and summed by an add
+        let toward = Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let dim = || Light::new(ConstantTexture::splat(1.0));
+        let bright = || Light::new(ConstantTexture::splat(3.0));
+        let blended = MixMaterial::new(dim(), bright(), 0.25).emitted(&toward, &record);
+        assert!((blended - Vec3::splat(1.5)).length() < 1e-4);
+        let added = AddMaterial::new(dim(), bright()).emitted(&toward, &record);
+        assert!((added - Vec3::splat(4.0)).length() < 1e-4);
+    }
+
+    #[test]
+    fn test_normal_map_records_normal_for_mixed_scatterer() {
+        let mut rng = thread_rng();
+        let ray = Ray::new(Vec3::new(-1.0, 0.0, 1.0), Vec3::new(1.0, 0.0, -1.0), 0.0);
+        let mix = MixMaterial::new(Diffuse::new(ConstantTexture::splat(0.5), 0.0),
+                                   Diffuse::new(ConstantTexture::splat(0.5), 1.0),
+                                   0.5);
+        let mapped = NormalMap::new(mix, ConstantTexture::new(1.0, 0.5, 1.0));
+
+        let scatter_record = mapped.scatter(&ray, &flat_hit(), &mut rng).unwrap();
+        let tilted = Vec3::new(1.0, 0.0, 1.0).normalize();
+        assert!((scatter_record.shading_normal.unwrap() - tilted).length() < 1e-4);
+        assert!(scatter_record.scatterer.is_some());
+    }
+
+    #[test]
+    fn test_subsurface_exits_through_dielectric() {
+        let mut rng = thread_rng();
+        let skin = Subsurface::new(Vec3::one(), Vec3::splat(0.5), 1.5);
+
+        // leaving at 60 degrees from the normal is past the critical angle of 41.8 degrees
+        let grazing = Ray::new(Vec3::zero(), Vec3::new(3.0f32.sqrt(), 0.0, 1.0), 0.0);
+        for _ in 0..16 {
+            let record = skin.scatter(&grazing, &flat_hit(), &mut rng).unwrap();
+            assert!(record.specular);
+            assert!(record.specular_ray.direction.z() < 0.0);
+        }
+
+        // a white slab in a furnace of unit radiance returns all the light it receives
+        let furnace = Light::new(ConstantTexture::splat(1.0));
+        let mut objects: Vec<Arc<dyn Hitable>> =
+            vec![Arc::new(Sphere::new(Vec3::zero(), Vec3::zero(), 1.0, skin, 0.0, 1.0)),
+                 Arc::new(FlipNormals::of(Sphere::new(Vec3::zero(),
+                                                      Vec3::zero(),
+                                                      100.0,
+                                                      furnace,
+                                                      0.0,
+                                                      1.0)))];
+        let world = BVH::new(&mut objects, 0.0, 1.0);
+        let light_source = Plane::new(Axis::XZ, 0.0, 0.0, 0.0, 0.0, 1000.0, Empty::new());
+        let environment = Environment::new(false, Vec::new());
+
+        let samples = 4000;
+        let mut radiance = Vec3::zero();
+        for i in 0..samples {
+            let offset = 0.9 * (i as f32 / samples as f32);
+            let ray = Ray::new(Vec3::new(offset, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+            radiance += render_path_integrator(ray,
+                                               &world,
+                                               64,
+                                               &light_source,
+                                               &environment,
+                                               None,
+                                               &mut rng);
+        }
+        radiance /= samples as f32;
+
+        assert!((radiance.x() - 1.0).abs() < 0.05);
     }
 }
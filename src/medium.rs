@@ -0,0 +1,445 @@
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use glam::Vec3;
+use rand::rngs::ThreadRng;
+use rand::Rng;
+
+use basis::OrthonormalBasis;
+use grid::VoxelGrid;
+use ray::Ray;
+use spectrum;
+use texture::Texture;
+use utils;
+
+/// The Henyey-Greenstein phase function
+///
+/// An anisotropy of zero scatters light equally in all directions while
+/// positive values favor forward scattering and negative values favor
+/// back scattering.
+///
+/// Reference:
+/// Louis Henyey, Jesse Greenstein
+/// Diffuse Radiation in the Galaxy
+/// Astrophysical Journal Vol. 93, 1941
+#[derive(Clone, Copy)]
+pub struct HenyeyGreenstein {
+    pub g: f32,
+}
+
+impl HenyeyGreenstein {
+    pub fn new(g: f32) -> HenyeyGreenstein {
+        HenyeyGreenstein { g: utils::clamp(g, -0.99, 0.99) }
+    }
+
+    /// Evaluate the phase function for the cosine between the incoming
+    /// direction of travel and the scattered direction
+    pub fn evaluate(&self, cos_theta: f32) -> f32 {
+        let g = self.g;
+        let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+        (1.0 - g * g) / (4.0 * PI * denominator * denominator.sqrt())
+    }
+
+    /// Sample a scattered direction for a ray travelling in the given direction
+    ///
+    /// The phase function is sampled exactly, so the sampling weight is one.
+    ///
+    /// See Section 15.2.3 in Physically Based Rendering, Third Edition.
+    pub fn sample(&self, direction: Vec3, rng: &mut ThreadRng) -> Vec3 {
+        let g = self.g;
+        let (r1, r2) = (rng.gen::<f32>(), rng.gen::<f32>());
+
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * r1
+        } else {
+            let term = (1.0 - g * g) / (1.0 - g + 2.0 * g * r1);
+            (1.0 + g * g - term * term) / (2.0 * g)
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * r2;
+
+        let uvw = OrthonormalBasis::new(&direction);
+        uvw.local(&Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta))
+    }
+}
+
+/// The spatial variation of a medium's density
+#[derive(Clone)]
+pub enum Density {
+    /// A density of one everywhere
+    Homogeneous,
+    /// A density interpolated from a voxel grid
+    Grid(Arc<VoxelGrid>),
+    /// A density read from the first channel of a texture and clamped to maximum
+    Texture {
+        texture: Arc<dyn Texture>,
+        maximum: f32,
+    },
+    /// A density of one below base that falls off exponentially with height
+    HeightFalloff {
+        base: f32,
+        falloff: f32,
+    },
+}
+
+impl Density {
+    pub fn at(&self, p: &Vec3) -> f32 {
+        match self {
+            Density::Homogeneous => 1.0,
+            Density::Grid(grid) => grid.lookup(p),
+            Density::Texture { texture, maximum } => {
+                texture.scalar(0.0, 0.0, p, 0.0).max(0.0).min(*maximum)
+            }
+            Density::HeightFalloff { base, falloff } => height_falloff(p.y(), *base, *falloff),
+        }
+    }
+
+    /// An upper bound of the density used as the majorant during tracking
+    pub fn maximum(&self) -> f32 {
+        match self {
+            Density::Homogeneous => 1.0,
+            Density::Grid(grid) => grid.maximum,
+            Density::Texture { maximum, .. } => *maximum,
+            Density::HeightFalloff { .. } => 1.0,
+        }
+    }
+
+    /// Split a ray up to t_max into segments with their own density bound
+    ///
+    /// Grids are split at the bricks they are made of and height falloff
+    /// is split every time the density changes by a factor of e, bounding
+    /// each segment by the density at its lowest point. Other densities
+    /// cover the whole ray with a single segment. The visitor returns false
+    /// to stop early.
+    pub fn segments<F>(&self, ray: &Ray, t_max: f32, mut visit: F)
+        where F: FnMut(f32, f32, f32) -> bool
+    {
+        match self {
+            Density::Grid(grid) => grid.traverse_bricks(ray, t_max, visit),
+            Density::HeightFalloff { base, falloff } => {
+                let (height, slope) = (ray.origin.y(), ray.direction.y());
+                let step = if slope.abs() * falloff > 0.0 {
+                    1.0 / (falloff * slope.abs())
+                } else {
+                    t_max
+                };
+
+                let mut start = 0.0;
+                while start < t_max {
+                    let end = (start + step).min(t_max);
+                    let lowest = height + slope * if slope > 0.0 { start } else { end };
+                    let maximum = height_falloff(lowest, *base, *falloff);
+
+                    if slope >= 0.0 && maximum < 1e-6 {
+                        return;
+                    }
+                    if slope <= 0.0 && maximum >= 1.0 {
+                        visit(start, t_max, maximum);
+                        return;
+                    }
+                    if !visit(start, end, maximum) {
+                        return;
+                    }
+                    start = end;
+                }
+            }
+            _ => {
+                visit(0.0, t_max, self.maximum());
+            }
+        }
+    }
+}
+
+/// The density of fog that thins out exponentially above the base height
+fn height_falloff(height: f32, base: f32, falloff: f32) -> f32 {
+    (-falloff * (height - base).max(0.0)).exp()
+}
+
+/// The number of entries in the lookup table of blackbody colors
+const BLACKBODY_TABLE_SIZE: usize = 1024;
+
+/// The radiance emitted by a medium
+#[derive(Clone)]
+pub enum Emission {
+    None,
+    /// Radiance read from a texture at each point in the medium
+    Texture(Arc<dyn Texture>),
+    /// Blackbody radiance of a temperature grid in Kelvin
+    ///
+    /// The colors are tabulated up to the maximum temperature of the grid
+    /// and scaled to convert Planck's law to the units of the scene.
+    Blackbody {
+        temperature: Arc<VoxelGrid>,
+        scale: f32,
+        table: Arc<Vec<Vec3>>,
+    },
+}
+
+impl Emission {
+    pub fn at(&self, p: &Vec3) -> Vec3 {
+        match self {
+            Emission::None => Vec3::zero(),
+            Emission::Texture(texture) => texture.value(0.0, 0.0, p, 0.0),
+            Emission::Blackbody { temperature,
+                                  scale,
+                                  table, } => {
+                if temperature.maximum <= 0.0 {
+                    return Vec3::zero();
+                }
+
+                let last = (table.len() - 1) as f32;
+                let position = (temperature.lookup(p) / temperature.maximum * last).max(0.0)
+                                                                                   .min(last);
+                let i = (position as usize).min(table.len() - 2);
+                let t = position - i as f32;
+
+                *scale * ((1.0 - t) * table[i] + t * table[i + 1])
+            }
+        }
+    }
+}
+
+/// The outcome of tracking a ray through a medium
+pub struct MediumSample {
+    /// The distance along the ray of a real scattering event, if any
+    pub t: Option<f32>,
+    /// The throughput weight of the tracked segment
+    pub weight: Vec3,
+    /// The radiance emitted toward the ray's origin along the tracked segment
+    pub emission: Vec3,
+}
+
+/// A participating medium that absorbs and scatters light
+///
+/// The coefficients are given per unit of distance for a density of one
+/// and are scaled by the density at each point. Emissive media emit in
+/// proportion to their absorption, as a medium in thermal equilibrium does.
+#[derive(Clone)]
+pub struct Medium {
+    pub sigma_a: Vec3,
+    pub sigma_s: Vec3,
+    pub phase: HenyeyGreenstein,
+    pub density: Density,
+    pub emission: Emission,
+}
+
+impl Medium {
+    pub fn new(sigma_a: Vec3, sigma_s: Vec3, g: f32, density: Density) -> Medium {
+        Medium { sigma_a,
+                 sigma_s,
+                 phase: HenyeyGreenstein::new(g),
+                 density,
+                 emission: Emission::None }
+    }
+
+    /// Create fog that is densest below the base height and thins out above it
+    ///
+    /// The density falls by a factor of e every 1 / falloff units of height.
+    pub fn height_fog(sigma_a: Vec3, sigma_s: Vec3, g: f32, base: f32, falloff: f32) -> Medium {
+        Medium::new(sigma_a, sigma_s, g, Density::HeightFalloff { base, falloff })
+    }
+
+    /// Make the medium emit the radiance read from a texture
+    pub fn with_emission<T: Texture + 'static>(mut self, texture: T) -> Medium {
+        self.emission = Emission::Texture(Arc::new(texture));
+        self
+    }
+
+    /// Make the medium glow like a blackbody at the temperatures of a grid
+    ///
+    /// This renders fire and explosions from the temperature channel of a
+    /// simulation. Planck's law is in W / (sr m^2 nm), so scale is usually
+    /// small to bring the brightest flames into the range of the scene.
+    pub fn with_blackbody(mut self, temperature: VoxelGrid, scale: f32) -> Medium {
+        let last = (BLACKBODY_TABLE_SIZE - 1) as f32;
+        let table = (0..BLACKBODY_TABLE_SIZE).map(|i| {
+                                                 spectrum::blackbody_rgb(temperature.maximum
+                                                                         * i as f32
+                                                                         / last)
+                                             })
+                                             .collect();
+
+        self.emission = Emission::Blackbody { temperature: Arc::new(temperature),
+                                              scale,
+                                              table: Arc::new(table) };
+        self
+    }
+
+    /// Create a medium with the same density everywhere
+    pub fn homogeneous(sigma_a: Vec3, sigma_s: Vec3, g: f32) -> Medium {
+        Medium::new(sigma_a, sigma_s, g, Density::Homogeneous)
+    }
+
+    fn sigma_t(&self) -> Vec3 {
+        self.sigma_a + self.sigma_s
+    }
+
+    /// Track a ray through the medium up to t_max
+    ///
+    /// Tentative collisions are sampled against a single majorant for all
+    /// channels within each segment of the density. At each collision we
+    /// choose between a real scattering event and a null collision with
+    /// probabilities proportional to the current path weight, and weight the
+    /// choice so the estimator stays unbiased for chromatic and heterogeneous
+    /// media. Absorption is accounted for in the weights rather than by
+    /// terminating the path, and emission is accumulated at every tentative
+    /// collision with the collision estimator.
+    ///
+    /// Reference:
+    /// Peter Kutz, Ralf Habel, Yining Karl Li, Jan Novák
+    /// Spectral and Decomposition Tracking for Rendering Heterogeneous Volumes
+    /// ACM Transactions on Graphics Vol. 36 Issue 4, 2017
+    pub fn sample(&self, ray: &Ray, t_max: f32, rng: &mut ThreadRng) -> MediumSample {
+        let mut sample = MediumSample { t: None,
+                                        weight: Vec3::one(),
+                                        emission: Vec3::zero() };
+        let emissive = !matches!(self.emission, Emission::None);
+        let average = |v: Vec3| (v.x() + v.y() + v.z()) / 3.0;
+
+        self.density.segments(ray, t_max, |start, end, maximum| {
+            let majorant = self.sigma_t().max_element() * maximum;
+            if majorant <= 0.0 {
+                return true;
+            }
+
+            let mut t = start;
+            loop {
+                t -= (1.0 - rng.gen::<f32>()).ln() / majorant;
+                if t >= end {
+                    return true;
+                }
+
+                let point = ray.point_at_parameter(t);
+                let density = self.density.at(&point);
+                let sigma_s = density * self.sigma_s;
+
+                if emissive {
+                    let sigma_a = density * self.sigma_a;
+                    let emitted = sigma_a * self.emission.at(&point) / majorant;
+                    sample.emission += sample.weight * emitted;
+                }
+                let sigma_n = Vec3::splat(majorant) - density * self.sigma_t();
+
+                let scatter = average(sample.weight * sigma_s);
+                let null = average(sample.weight * sigma_n);
+                if scatter + null <= 0.0 {
+                    sample.weight = Vec3::zero();
+                    return false;
+                }
+
+                let scatter_probability = scatter / (scatter + null);
+                if rng.gen::<f32>() < scatter_probability {
+                    sample.weight *= sigma_s / (majorant * scatter_probability);
+                    sample.t = Some(t);
+                    return false;
+                }
+                sample.weight *= sigma_n / (majorant * (1.0 - scatter_probability));
+            }
+        });
+
+        sample
+    }
+
+    /// Estimate the transmittance along a ray up to t_max
+    ///
+    /// Homogeneous media use Beer's law directly while heterogeneous media
+    /// use ratio tracking, which multiplies the probability of a null
+    /// collision at each tentative collision.
+    ///
+    /// Reference:
+    /// Jan Novák, Andrew Selle, Wojciech Jarosz
+    /// Residual Ratio Tracking for Estimating Attenuation in Participating Media
+    /// ACM Transactions on Graphics Vol. 33 Issue 6, 2014
+    pub fn transmittance(&self, ray: &Ray, t_max: f32, rng: &mut ThreadRng) -> Vec3 {
+        if let Density::Homogeneous = self.density {
+            let sigma_t = self.sigma_t();
+            return Vec3::new((-sigma_t.x() * t_max).exp(),
+                             (-sigma_t.y() * t_max).exp(),
+                             (-sigma_t.z() * t_max).exp());
+        }
+
+        let mut transmittance = Vec3::one();
+
+        self.density.segments(ray, t_max, |start, end, maximum| {
+            let majorant = self.sigma_t().max_element() * maximum;
+            if majorant <= 0.0 {
+                return true;
+            }
+
+            let mut t = start;
+            loop {
+                t -= (1.0 - rng.gen::<f32>()).ln() / majorant;
+                if t >= end {
+                    return true;
+                }
+
+                let density = self.density.at(&ray.point_at_parameter(t));
+                transmittance *= Vec3::one() - density * self.sigma_t() / majorant;
+
+                if transmittance.max_element() <= 0.0 {
+                    transmittance = Vec3::zero();
+                    return false;
+                }
+            }
+        });
+
+        transmittance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::thread_rng;
+    use texture::ConstantTexture;
+
+    #[test]
+    fn test_ratio_tracking_matches_beers_law() {
+        let mut rng = thread_rng();
+        let density = Density::Texture { texture: Arc::new(ConstantTexture::splat(0.5)),
+                                         maximum: 2.0 };
+        let medium = Medium::new(Vec3::splat(0.5), Vec3::splat(0.5), 0.0, density);
+        let ray = Ray::new(Vec3::zero(), Vec3::new(0.0, 0.0, 1.0), 0.0);
+
+        let samples = 20000;
+        let mut transmittance = Vec3::zero();
+        for _ in 0..samples {
+            transmittance += medium.transmittance(&ray, 2.0, &mut rng);
+        }
+        transmittance /= samples as f32;
+
+        assert!((transmittance.x() - (-1.0f32).exp()).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_height_fog_matches_exponential_integral() {
+        let mut rng = thread_rng();
+        let (base, falloff) = (1.0, 0.5);
+        let medium = Medium::height_fog(Vec3::splat(0.25), Vec3::splat(0.75), 0.0, base, falloff);
+
+        let density = &medium.density;
+        assert!((density.at(&Vec3::new(3.0, -2.0, 1.0)) - 1.0).abs() < 1e-6);
+        assert!((density.at(&Vec3::new(0.0, base + 1.0 / falloff, 0.0)) - (-1.0f32).exp()).abs()
+                < 1e-6);
+
+        // rising from the base the optical depth is (1 - exp(-falloff * y)) / (falloff * slope)
+        let slope = 0.5f32.sqrt();
+        let rising = Ray::new(Vec3::new(0.0, base, 0.0), Vec3::new(1.0, 1.0, 0.0), 0.0);
+        let rising_depth = (1.0 - (-falloff * slope * 4.0).exp()) / (falloff * slope);
+
+        // falling from two units above the base into the constant fog below it
+        let falling = Ray::new(Vec3::new(0.0, base + 2.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        let falling_depth = (1.0 - (-falloff * 2.0).exp()) / falloff + 2.0;
+
+        let samples = 20000;
+        for &(ray, depth) in &[(&rising, rising_depth), (&falling, falling_depth)] {
+            let mut transmittance = Vec3::zero();
+            for _ in 0..samples {
+                transmittance += medium.transmittance(ray, 4.0, &mut rng);
+            }
+            transmittance /= samples as f32;
+
+            assert!((transmittance.x() - (-depth).exp()).abs() < 0.01);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::f32;
+
+use glam::Vec3;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+/// The twelve gradient directions toward the edges of a cube
+const GRADIENTS: [[f32; 3]; 12] = [[1.0, 1.0, 0.0],
+                                   [-1.0, 1.0, 0.0],
+                                   [1.0, -1.0, 0.0],
+                                   [-1.0, -1.0, 0.0],
+                                   [1.0, 0.0, 1.0],
+                                   [-1.0, 0.0, 1.0],
+                                   [1.0, 0.0, -1.0],
+                                   [-1.0, 0.0, -1.0],
+                                   [0.0, 1.0, 1.0],
+                                   [0.0, -1.0, 1.0],
+                                   [0.0, 1.0, -1.0],
+                                   [0.0, -1.0, -1.0]];
+
+/// The size of the permutation table used to hash lattice points
+const PERMUTATION_SIZE: usize = 256;
+
+#[derive(Clone)]
+pub struct Perlin {
+    permutation: Vec<usize>,
+}
+
+impl Perlin {
+    /// Create a new gradient noise generator
+    ///
+    /// The seed shuffles the permutation table so that different seeds
+    /// produce uncorrelated noise.
+    pub fn new(seed: u64) -> Perlin {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut permutation: Vec<usize> = (0..PERMUTATION_SIZE).collect();
+        permutation.shuffle(&mut rng);
+
+        let repeated = permutation.clone();
+        permutation.extend(repeated);
+
+        Perlin { permutation }
+    }
+
+    fn hash(&self, x: i32, y: i32, z: i32) -> usize {
+        let mask = PERMUTATION_SIZE as i32 - 1;
+        let xi = self.permutation[(x & mask) as usize];
+        let yi = self.permutation[xi + (y & mask) as usize];
+        self.permutation[yi + (z & mask) as usize]
+    }
+
+    fn gradient(&self, x: i32, y: i32, z: i32, offset: Vec3) -> f32 {
+        let [gx, gy, gz] = GRADIENTS[self.hash(x, y, z) % GRADIENTS.len()];
+        gx * offset.x() + gy * offset.y() + gz * offset.z()
+    }
+
+    /// Evaluate gradient noise at the given point
+    ///
+    /// The result lies approximately in [-1, 1] and is zero at every lattice point.
+    ///
+    /// Reference:
+    /// Ken Perlin
+    /// Improving Noise
+    /// ACM Transactions on Graphics Vol. 21 Issue 3, 2002
+    pub fn noise(&self, p: &Vec3) -> f32 {
+        let (x, y, z) = (p.x().floor(), p.y().floor(), p.z().floor());
+        let (xi, yi, zi) = (x as i32, y as i32, z as i32);
+        let offset = *p - Vec3::new(x, y, z);
+
+        let fade = |t: f32| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+        let lerp = |t: f32, a: f32, b: f32| a + t * (b - a);
+        let (u, v, w) = (fade(offset.x()), fade(offset.y()), fade(offset.z()));
+
+        let corner = |i: i32, j: i32, k: i32| {
+            self.gradient(xi + i,
+                          yi + j,
+                          zi + k,
+                          offset - Vec3::new(i as f32, j as f32, k as f32))
+        };
+
+        lerp(w,
+             lerp(v,
+                  lerp(u, corner(0, 0, 0), corner(1, 0, 0)),
+                  lerp(u, corner(0, 1, 0), corner(1, 1, 0))),
+             lerp(v,
+                  lerp(u, corner(0, 0, 1), corner(1, 0, 1)),
+                  lerp(u, corner(0, 1, 1), corner(1, 1, 1))))
+    }
+
+    /// Sum octaves of noise with doubling frequency and halving amplitude
+    pub fn fbm(&self, p: &Vec3, octaves: u32) -> f32 {
+        let mut sum = 0.0;
+        let mut point = *p;
+        let mut amplitude = 1.0;
+
+        for _ in 0..octaves {
+            sum += amplitude * self.noise(&point);
+            amplitude *= 0.5;
+            point *= 2.0;
+        }
+
+        sum
+    }
+
+    /// Sum octaves of the absolute value of noise
+    ///
+    /// The creases where the noise changes sign give turbulence its
+    /// billowy look compared to plain fractal Brownian motion.
+    pub fn turbulence(&self, p: &Vec3, octaves: u32) -> f32 {
+        let mut sum = 0.0;
+        let mut point = *p;
+        let mut amplitude = 1.0;
+
+        for _ in 0..octaves {
+            sum += amplitude * self.noise(&point).abs();
+            amplitude *= 0.5;
+            point *= 2.0;
+        }
+
+        sum
+    }
+}
+
+/// The distances to the nearest feature points and the cell of the nearest one
+pub struct WorleySample {
+    pub f1: f32,
+    pub f2: f32,
+    pub cell: u32,
+}
+
+#[derive(Clone)]
+pub struct Worley {
+    seed: u32,
+}
+
+impl Worley {
+    /// Create a new cellular noise generator with the given seed
+    pub fn new(seed: u64) -> Worley {
+        Worley { seed: (seed ^ (seed >> 32)) as u32 }
+    }
+
+    /// Find the feature point of the given lattice cell
+    fn feature_point(&self, x: i32, y: i32, z: i32) -> (Vec3, u32) {
+        let cell = hash(hash(hash(self.seed ^ x as u32) ^ y as u32) ^ z as u32);
+        let jitter = Vec3::new(to_unit(hash(cell)),
+                               to_unit(hash(cell ^ 0x68e3_1da4)),
+                               to_unit(hash(cell ^ 0xb529_7a4d)));
+
+        (Vec3::new(x as f32, y as f32, z as f32) + jitter, cell)
+    }
+
+    /// Evaluate cellular noise at the given point
+    ///
+    /// Each lattice cell holds one randomly placed feature point and the
+    /// 27 cells around the point are searched for the two closest.
+    ///
+    /// Reference:
+    /// Steven Worley
+    /// A Cellular Texture Basis Function
+    /// SIGGRAPH 1996
+    pub fn sample(&self, p: &Vec3) -> WorleySample {
+        let (x, y, z) = (p.x().floor() as i32, p.y().floor() as i32, p.z().floor() as i32);
+
+        let mut nearest = WorleySample { f1: f32::MAX,
+                                         f2: f32::MAX,
+                                         cell: 0 };
+
+        for i in -1..=1 {
+            for j in -1..=1 {
+                for k in -1..=1 {
+                    let (feature, cell) = self.feature_point(x + i, y + j, z + k);
+                    let distance = (feature - *p).length();
+
+                    if distance < nearest.f1 {
+                        nearest.f2 = nearest.f1;
+                        nearest.f1 = distance;
+                        nearest.cell = cell;
+                    } else if distance < nearest.f2 {
+                        nearest.f2 = distance;
+                    }
+                }
+            }
+        }
+
+        nearest
+    }
+}
+
+/// Hash an integer with good avalanche behavior
+///
+/// Reference: https://nullprogram.com/blog/2018/07/31/
+pub fn hash(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^= x >> 16;
+    x
+}
+
+/// Map a hashed integer to a float in [0, 1)
+pub fn to_unit(x: u32) -> f32 {
+    (x >> 8) as f32 / (1u32 << 24) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_perlin_noise_is_seeded() {
+        let point = Vec3::new(0.3, 1.7, -2.2);
+
+        assert_eq!(Perlin::new(7).noise(&point), Perlin::new(7).noise(&point));
+        assert_ne!(Perlin::new(7).noise(&point), Perlin::new(8).noise(&point));
+        assert_eq!(Perlin::new(7).noise(&Vec3::new(1.0, 2.0, 3.0)), 0.0);
+    }
+}
@@ -83,6 +83,8 @@ impl Hitable for Plane {
                 }
 
                 let normal = Vec3::new(0.0, 0.0, 1.0);
+                let dpdu = Vec3::new(self.r1 - self.r0, 0.0, 0.0);
+                let dpdv = Vec3::new(0.0, self.s1 - self.s0, 0.0);
 
                 let record = HitRecord::new(t,
                                             (x - self.r0) / (self.r1 - self.r0),
@@ -90,9 +92,14 @@ impl Hitable for Plane {
                                             ray.point_at_parameter(t),
                                             normal,
                                             normal,
-                                            self.material.clone());
+                                            self.material.clone())
+                             .with_derivatives(dpdu, dpdv);
 
-                Some(record)
+                if record.is_opaque(ray) {
+                    Some(record)
+                } else {
+                    None
+                }
             }
             Axis::YZ => {
                 let t = (self.k - ray.origin.x()) / ray.direction.x();
@@ -109,6 +116,8 @@ impl Hitable for Plane {
                 }
 
                 let normal = Vec3::new(1.0, 0.0, 0.0);
+                let dpdu = Vec3::new(0.0, self.r1 - self.r0, 0.0);
+                let dpdv = Vec3::new(0.0, 0.0, self.s1 - self.s0);
 
                 let record = HitRecord::new(t,
                                             (y - self.r0) / (self.r1 - self.r0),
@@ -116,9 +125,14 @@ impl Hitable for Plane {
                                             ray.point_at_parameter(t),
                                             normal,
                                             normal,
-                                            self.material.clone());
+                                            self.material.clone())
+                             .with_derivatives(dpdu, dpdv);
 
-                Some(record)
+                if record.is_opaque(ray) {
+                    Some(record)
+                } else {
+                    None
+                }
             }
             Axis::XZ => {
                 let t = (self.k - ray.origin.y()) / ray.direction.y();
@@ -135,6 +149,8 @@ impl Hitable for Plane {
                 }
 
                 let normal = Vec3::new(0.0, 1.0, 0.0);
+                let dpdu = Vec3::new(self.r1 - self.r0, 0.0, 0.0);
+                let dpdv = Vec3::new(0.0, 0.0, self.s1 - self.s0);
 
                 let record = HitRecord::new(t,
                                             (x - self.r0) / (self.r1 - self.r0),
@@ -142,9 +158,14 @@ impl Hitable for Plane {
                                             ray.point_at_parameter(t),
                                             normal,
                                             normal,
-                                            self.material.clone());
+                                            self.material.clone())
+                             .with_derivatives(dpdu, dpdv);
 
-                Some(record)
+                if record.is_opaque(ray) {
+                    Some(record)
+                } else {
+                    None
+                }
             }
         }
     }
@@ -8,6 +8,9 @@ pub struct Ray {
     pub direction: Vec3,
     pub time: f32,
     pub inverse_direction: Vec3,
+    pub wavelength: Option<f32>,
+    pub width: f32,
+    pub spread: f32,
 }
 
 impl Ray {
@@ -16,7 +19,31 @@ impl Ray {
         Ray { origin: origin,
               direction: direction.normalize(),
               time: time,
-              inverse_direction: direction.reciprocal() }
+              inverse_direction: direction.normalize().reciprocal(),
+              wavelength: None,
+              width: 0.0,
+              spread: 0.0 }
+    }
+
+    /// Trace the ray as a cone for texture filtering
+    ///
+    /// The cone starts width units wide at the origin and widens by the
+    /// spread angle in radians for every unit it travels.
+    ///
+    /// Reference:
+    /// Tomas Akenine-Möller, Jim Nilsson, Magnus Andersson, Colin Barré-Brisebois,
+    /// Robert Toth, Tero Karras
+    /// Texture Level of Detail Strategies for Real-Time Ray Tracing
+    /// Ray Tracing Gems, Chapter 20
+    pub fn with_cone(mut self, width: f32, spread: f32) -> Ray {
+        self.width = width;
+        self.spread = spread;
+        self
+    }
+
+    /// Find the width of the ray's cone at the given parameter
+    pub fn cone_width(&self, parameter: f32) -> f32 {
+        self.width + self.spread * parameter
     }
 
     /// Find the point on the ray given the parameter of the direction vector
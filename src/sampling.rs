@@ -2,7 +2,7 @@ use rand::rngs::ThreadRng;
 use rand::Rng;
 use std::f32::consts::PI;
 
-use glam::Vec3;
+use glam::{Vec2, Vec3};
 
 pub fn cosine_sample_hemisphere(rng: &mut ThreadRng) -> Vec3 {
     let r1 = rng.gen::<f32>();
@@ -43,3 +43,29 @@ pub fn uniform_sample_sphere(rng: &mut ThreadRng) -> Vec3 {
 
     Vec3::new(x, y, z)
 }
+
+/// Map a uniform sample of the square to a uniform sample of the unit disk
+///
+/// The concentric mapping keeps nearby points of the square close on the
+/// disk, unlike sampling the radius and angle directly.
+///
+/// Reference:
+/// Peter Shirley, Kenneth Chiu
+/// A Low Distortion Map Between Disk and Square
+/// Journal of Graphics Tools Vol. 2 Issue 3, 1997
+pub fn concentric_sample_disk(rng: &mut ThreadRng) -> Vec2 {
+    let a = 2.0 * rng.gen::<f32>() - 1.0;
+    let b = 2.0 * rng.gen::<f32>() - 1.0;
+
+    if a == 0.0 && b == 0.0 {
+        return Vec2::zero();
+    }
+
+    let (radius, theta) = if a.abs() > b.abs() {
+        (a, 0.25 * PI * (b / a))
+    } else {
+        (b, 0.5 * PI - 0.25 * PI * (a / b))
+    };
+
+    Vec2::new(radius * theta.cos(), radius * theta.sin())
+}
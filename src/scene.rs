@@ -1,43 +1,37 @@
 use std::f32;
 use std::sync::Arc;
 
-use glam::Vec3;
+use glam::{Mat4, Quat, Vec3};
 
 use bvh::BVH;
-use camera::Camera;
-use hitable::FlipNormals;
-use materials::{Diffuse, Empty, Light, Reflective, Refractive};
+use camera::{Camera, CameraKeyframe, Focus, Perspective, Physical, Placement, Sensor};
+use hitable::{FlipNormals, Hitable};
+use instance::Instance;
+use materials::{Diffuse, Empty, Interior, Light, Reflective, Refractive};
+use medium::Medium;
 use plane::{Axis, Plane};
 use rectangle::Rectangle;
 use sphere::Sphere;
 use texture::{ConstantTexture, ImageTexture};
-use transformations::{Rotate, Translate};
+use transformations::{AnimatedTransform, Keyframe, Transform};
 use triangle::TriangleMesh;
 use volume::Volume;
 use world::World;
 
-pub fn three_spheres_scene(width: usize, height: usize) -> (String, Camera, BVH, Plane) {
+pub fn three_spheres_scene(width: usize, height: usize) -> (String, Box<dyn Camera>, BVH, Plane) {
     let origin = Vec3::new(0.0, 3.0, 6.0);
     let lookat = Vec3::new(0.0, 0.0, -1.5);
     let view = Vec3::new(0.0, 1.0, 0.0);
     let fov = 20.0;
-    let aspect_ratio = (width / height) as f32;
+    let aspect_ratio = width as f32 / height as f32;
     let aperture = 0.1;
     let focus_distance = 10.0;
     let time0 = 0.0;
     let time1 = 1.0;
     let atmosphere = true;
 
-    let camera = Camera::new(origin,
-                             lookat,
-                             view,
-                             fov,
-                             aspect_ratio,
-                             aperture,
-                             focus_distance,
-                             time0,
-                             time1,
-                             atmosphere);
+    let placement = Placement::new(origin, lookat, view, time0, time1, atmosphere);
+    let camera = Perspective::new(placement, fov, aspect_ratio, aperture, focus_distance);
 
     let mut world = World::new();
 
@@ -73,31 +67,23 @@ pub fn three_spheres_scene(width: usize, height: usize) -> (String, Camera, BVH,
 
     let light = Plane::new(Axis::XY, 0.0, 0.0, 0.0, 0.0, 0.0, Empty::new());
 
-    (String::from("Three Spheres"), camera, bvh, light)
+    (String::from("Three Spheres"), Box::new(camera), bvh, light)
 }
 
-pub fn random_spheres_scene(width: usize, height: usize) -> (String, Camera, BVH, Plane) {
+pub fn random_spheres_scene(width: usize, height: usize) -> (String, Box<dyn Camera>, BVH, Plane) {
     let origin = Vec3::new(13.0, 2.0, 3.0);
     let lookat = Vec3::new(0.0, 0.0, 0.0);
     let view = Vec3::new(0.0, 1.0, 0.0);
     let fov = 20.0;
-    let aspect_ratio = (width / height) as f32;
+    let aspect_ratio = width as f32 / height as f32;
     let aperture = 0.1;
     let focus_distance = 10.0;
     let time0 = 0.0;
     let time1 = 1.0;
     let atmosphere = true;
 
-    let camera = Camera::new(origin,
-                             lookat,
-                             view,
-                             fov,
-                             aspect_ratio,
-                             aperture,
-                             focus_distance,
-                             time0,
-                             time1,
-                             atmosphere);
+    let placement = Placement::new(origin, lookat, view, time0, time1, atmosphere);
+    let camera = Perspective::new(placement, fov, aspect_ratio, aperture, focus_distance);
 
     let mut world = World::new();
 
@@ -186,31 +172,79 @@ pub fn random_spheres_scene(width: usize, height: usize) -> (String, Camera, BVH
 
     let light = Plane::new(Axis::XY, 0.0, 0.0, 0.0, 0.0, 0.0, Empty::new());
 
-    (String::from("Random Spheres"), camera, bvh, light)
+    (String::from("Random Spheres"), Box::new(camera), bvh, light)
 }
 
-pub fn earth_scene(width: usize, height: usize) -> (String, Camera, World, Plane) {
+pub fn instances_scene(width: usize, height: usize) -> (String, Box<dyn Camera>, BVH, Plane) {
+    let origin = Vec3::new(0.0, 12.0, 40.0);
+    let lookat = Vec3::new(0.0, 0.0, 0.0);
+    let view = Vec3::new(0.0, 1.0, 0.0);
+    let fov = 40.0;
+    let aspect_ratio = width as f32 / height as f32;
+    let aperture = 0.0;
+    let focus_distance = 40.0;
+    let time0 = 0.0;
+    let time1 = 1.0;
+    let atmosphere = true;
+
+    let placement = Placement::new(origin, lookat, view, time0, time1, atmosphere);
+    let camera = Perspective::new(placement, fov, aspect_ratio, aperture, focus_distance);
+
+    let mut world = World::new();
+
+    world.add(Sphere::new(Vec3::new(0.0, -1000.0, 0.0),
+                          Vec3::new(0.0, -1000.0, 0.0),
+                          1000.0,
+                          Diffuse::new(ConstantTexture::new(0.5, 0.5, 0.5), 0.0),
+                          0.0,
+                          1.0));
+
+    let suzanne: Arc<dyn Hitable> =
+        Arc::new(TriangleMesh::from("suzanne.obj",
+                                    Arc::new(Diffuse::new(ConstantTexture::new(0.8, 0.8, 0.8),
+                                                          0.0))));
+
+    for a in -16..16 {
+        for b in -16..16 {
+            let scale = 0.5 + 0.5 * rand::random::<f32>();
+            let rotation = Quat::from_rotation_y(2.0 * f32::consts::PI * rand::random::<f32>());
+            let translation = Vec3::new(3.0 * a as f32, scale, 3.0 * b as f32);
+            let transform =
+                Mat4::from_scale_rotation_translation(Vec3::splat(scale), rotation, translation);
+            let instance = Instance::new(suzanne.clone(), transform);
+
+            if rand::random::<f32>() < 0.5 {
+                world.add(instance.with_material(Diffuse::new(ConstantTexture::new(rand::random(),
+                                                                                   rand::random(),
+                                                                                   rand::random()),
+                                                              0.0)));
+            } else {
+                world.add(instance);
+            }
+        }
+    }
+
+    let bvh = BVH::new(&mut world.objects, 0.0, 1.0);
+
+    let light = Plane::new(Axis::XY, 0.0, 0.0, 0.0, 0.0, 0.0, Empty::new());
+
+    (String::from("Instances"), Box::new(camera), bvh, light)
+}
+
+pub fn earth_scene(width: usize, height: usize) -> (String, Box<dyn Camera>, World, Plane) {
     let origin = Vec3::new(13.0, 2.0, 3.0);
     let lookat = Vec3::new(0.0, 0.0, 0.0);
     let view = Vec3::new(0.0, 1.0, 0.0);
     let fov = 20.0;
-    let aspect_ratio = (width / height) as f32;
+    let aspect_ratio = width as f32 / height as f32;
     let aperture = 0.1;
     let focus_distance = 10.0;
     let time0 = 0.0;
     let time1 = 1.0;
     let atmosphere = false;
 
-    let camera = Camera::new(origin,
-                             lookat,
-                             view,
-                             fov,
-                             aspect_ratio,
-                             aperture,
-                             focus_distance,
-                             time0,
-                             time1,
-                             atmosphere);
+    let placement = Placement::new(origin, lookat, view, time0, time1, atmosphere);
+    let camera = Perspective::new(placement, fov, aspect_ratio, aperture, focus_distance);
 
     let mut world = World::new();
 
@@ -223,31 +257,23 @@ pub fn earth_scene(width: usize, height: usize) -> (String, Camera, World, Plane
 
     let light = Plane::new(Axis::XY, 0.0, 0.0, 0.0, 0.0, 0.0, Empty::new());
 
-    (String::from("Earth"), camera, world, light)
+    (String::from("Earth"), Box::new(camera), world, light)
 }
 
-pub fn motion_scene(width: usize, height: usize) -> (String, Camera, BVH, Plane) {
+pub fn motion_scene(width: usize, height: usize) -> (String, Box<dyn Camera>, BVH, Plane) {
     let origin = Vec3::new(13.0, 2.0, 3.0);
     let lookat = Vec3::new(0.0, 0.0, 0.0);
     let view = Vec3::new(0.0, 1.0, 0.0);
     let fov = 20.0;
-    let aspect_ratio = (width / height) as f32;
+    let aspect_ratio = width as f32 / height as f32;
     let aperture = 0.1;
     let focus_distance = 10.0;
     let time0 = 0.0;
     let time1 = 1.0;
     let atmosphere = true;
 
-    let camera = Camera::new(origin,
-                             lookat,
-                             view,
-                             fov,
-                             aspect_ratio,
-                             aperture,
-                             focus_distance,
-                             time0,
-                             time1,
-                             atmosphere);
+    let placement = Placement::new(origin, lookat, view, time0, time1, atmosphere);
+    let camera = Perspective::new(placement, fov, aspect_ratio, aperture, focus_distance);
 
     let mut world = World::new();
 
@@ -282,35 +308,123 @@ pub fn motion_scene(width: usize, height: usize) -> (String, Camera, BVH, Plane)
                           0.0,
                           1.0));
 
+    let spin = AnimatedTransform::new(vec![Keyframe::new(0.0,
+                                                         Vec3::new(2.0, 0.5, 0.0),
+                                                         Quat::identity(),
+                                                         Vec3::one()),
+                                           Keyframe::new(1.0,
+                                                         Vec3::new(2.0, 1.0, 0.5),
+                                                         Quat::from_rotation_y(1.0),
+                                                         Vec3::one())]);
+    world.add(Transform::animated(spin,
+                                  Rectangle::new(Vec3::splat(-0.5),
+                                                 Vec3::splat(0.5),
+                                                 Arc::new(Diffuse::new(ConstantTexture::new(0.25,
+                                                                                            0.25,
+                                                                                            0.75),
+                                                                       0.0)))));
+
     let bvh = BVH::new(&mut world.objects, 0.0, 1.0);
 
     let light = Plane::new(Axis::XY, 0.0, 0.0, 0.0, 0.0, 0.0, Empty::new());
 
-    (String::from("Motion Blur"), camera, bvh, light)
+    (String::from("Motion Blur"), Box::new(camera), bvh, light)
+}
+
+/// A two second animation of a tumbling box circled by the camera
+///
+/// Render it with --frames=1:48 to write one image per frame at 24 frames
+/// per second. The BVH is built once over the whole animation and refit to
+/// the shutter interval of every frame.
+pub fn animation_scene(width: usize, height: usize) -> (String, Box<dyn Camera>, BVH, Plane) {
+    let origin = Vec3::new(0.0, 3.0, 10.0);
+    let lookat = Vec3::new(0.0, 1.0, 0.0);
+    let view = Vec3::new(0.0, 1.0, 0.0);
+    let fov = 30.0;
+    let aspect_ratio = width as f32 / height as f32;
+    let aperture = 0.0;
+    let focus_distance = 10.0;
+    let time0 = 0.0;
+    let time1 = 1.0 / 48.0;
+    let duration = 2.0;
+    let atmosphere = true;
+
+    let orbit = (0..=8).map(|i| {
+                           let time = duration * i as f32 / 8.0;
+                           let angle = 0.25 * f32::consts::PI * time;
+                           let position = Vec3::new(10.0 * angle.sin(), 3.0, 10.0 * angle.cos());
+                           CameraKeyframe::new(time, position, lookat)
+                       });
+    let placement = Placement::new(origin, lookat, view, time0, time1, atmosphere)
+        .with_keyframes(orbit.collect());
+    let camera = Perspective::new(placement, fov, aspect_ratio, aperture, focus_distance);
+
+    let mut world = World::new();
+
+    world.add(Sphere::new(Vec3::new(0.0, -1000.0, 0.0),
+                          Vec3::new(0.0, -1000.0, 0.0),
+                          1000.0,
+                          Diffuse::new(ConstantTexture::new(0.5, 0.5, 0.5), 0.0),
+                          0.0,
+                          1.0));
+
+    world.add(Sphere::new(Vec3::new(-2.5, 1.0, 0.0),
+                          Vec3::new(-2.5, 1.0, 0.0),
+                          1.0,
+                          Reflective::new(Vec3::new(0.7, 0.6, 0.5), 0.0),
+                          0.0,
+                          1.0));
+
+    let tumble = AnimatedTransform::new(vec![Keyframe::new(0.0,
+                                                           Vec3::new(1.5, 0.5, 0.0),
+                                                           Quat::identity(),
+                                                           Vec3::one()),
+                                             Keyframe::new(1.0,
+                                                           Vec3::new(1.5, 2.5, 0.0),
+                                                           Quat::from_rotation_x(3.0),
+                                                           Vec3::splat(1.5)),
+                                             Keyframe::new(duration,
+                                                           Vec3::new(1.5, 0.5, 0.0),
+                                                           Quat::from_rotation_x(6.0),
+                                                           Vec3::one())]);
+    world.add(Transform::animated(tumble,
+                                  Rectangle::new(Vec3::splat(-0.5),
+                                                 Vec3::splat(0.5),
+                                                 Arc::new(Diffuse::new(ConstantTexture::new(0.75,
+                                                                                            0.25,
+                                                                                            0.25),
+                                                                       0.0)))));
+
+    let bvh = BVH::new(&mut world.objects, 0.0, duration);
+
+    let light = Plane::new(Axis::XY, 0.0, 0.0, 0.0, 0.0, 0.0, Empty::new());
+
+    (String::from("Animation"), Box::new(camera), bvh, light)
 }
 
-pub fn simple_light_scene(width: usize, height: usize) -> (String, Camera, BVH, Plane) {
+pub fn simple_light_scene(width: usize, height: usize) -> (String, Box<dyn Camera>, BVH, Plane) {
     let origin = Vec3::new(13.0, 3.0, 3.0);
     let lookat = Vec3::new(0.0, 0.0, 0.0);
     let view = Vec3::new(0.0, 1.0, 0.0);
-    let fov = 50.0;
-    let aspect_ratio = (width / height) as f32;
-    let aperture = 0.1;
+    let aspect_ratio = width as f32 / height as f32;
+    let focal_length = 35.0;
+    let f_number = 4.0;
     let focus_distance = 10.0;
     let time0 = 0.0;
     let time1 = 1.0;
     let atmosphere = false;
 
-    let camera = Camera::new(origin,
-                             lookat,
-                             view,
-                             fov,
-                             aspect_ratio,
-                             aperture,
-                             focus_distance,
-                             time0,
-                             time1,
-                             atmosphere);
+    let placement = Placement::new(origin, lookat, view, time0, time1, atmosphere);
+    let camera = Physical::new(placement,
+                               aspect_ratio,
+                               focal_length,
+                               Sensor::full_frame(),
+                               f_number,
+                               focus_distance).with_shutter_speed(1.0 / 60.0)
+                                              .with_iso(400.0);
+
+    // the physical camera exposes for lights given in candela per square meter
+    let luminance = 1000.0;
 
     let mut world = World::new();
 
@@ -321,13 +435,16 @@ pub fn simple_light_scene(width: usize, height: usize) -> (String, Camera, BVH,
                           0.0,
                           1.0));
 
-    world.add(Translate::new(Vec3::new(0.0, 2.0, 0.0), Rotate::new(90.0, TriangleMesh::from("suzanne.obj",
-                          Arc::new(Diffuse::new(ConstantTexture::new(1.0, 0.0, 0.0), 0.0))))));
+    let suzanne = TriangleMesh::from("suzanne.obj",
+                                     Arc::new(Diffuse::new(ConstantTexture::new(1.0, 0.0, 0.0),
+                                                           0.0)));
+    world.add(Transform::rotate(Vec3::unit_y(), 90.0, suzanne)
+                  .then(Mat4::from_translation(Vec3::new(0.0, 2.0, 0.0))));
 
     world.add(Sphere::new(Vec3::new(0.0, 7.0, 0.0),
                           Vec3::new(0.0, 7.0, 0.0),
                           2.0,
-                          Light::new(ConstantTexture::new(4.0, 4.0, 4.0)),
+                          Light::new(ConstantTexture::new(luminance, luminance, luminance)),
                           0.0,
                           1.0));
 
@@ -337,39 +454,28 @@ pub fn simple_light_scene(width: usize, height: usize) -> (String, Camera, BVH,
                          1.0,
                          3.0,
                          -2.0,
-                         Light::new(ConstantTexture::new(4.0, 4.0, 4.0))));
+                         Light::new(ConstantTexture::new(luminance, luminance, luminance))));
 
     let bvh = BVH::new(&mut world.objects, 0.0, 1.0);
 
     let light = Light::new(ConstantTexture::new(0.0, 0.0, 0.0));
     let light_shape = Plane::new(Axis::XZ, 3.0, 5.0, 1.0, 3.0, -2.0, light);
 
-    (String::from("Simple Light"), camera, bvh, light_shape)
+    (String::from("Simple Light"), Box::new(camera), bvh, light_shape)
 }
 
-pub fn cornell_box_scene(width: usize, height: usize) -> (String, Camera, BVH, Plane) {
+pub fn cornell_box_scene(width: usize, height: usize) -> (String, Box<dyn Camera>, BVH, Plane) {
     let origin = Vec3::new(278.0, 278.0, -800.0);
     let lookat = Vec3::new(278.0, 278.0, 0.0);
     let view = Vec3::new(0.0, 1.0, 0.0);
     let fov = 40.0;
-    let aspect_ratio = (width / height) as f32;
+    let aspect_ratio = width as f32 / height as f32;
     let aperture = 0.0;
-    let focus_distance = 10.0;
+    let focus = Focus::Pixel(0.5, 0.5);
     let time0 = 0.0;
     let time1 = 1.0;
     let atmosphere = false;
 
-    let camera = Camera::new(origin,
-                             lookat,
-                             view,
-                             fov,
-                             aspect_ratio,
-                             aperture,
-                             focus_distance,
-                             time0,
-                             time1,
-                             atmosphere);
-
     let mut world = World::new();
 
     let roughness = 0.0;
@@ -395,44 +501,44 @@ pub fn cornell_box_scene(width: usize, height: usize) -> (String, Camera, BVH, P
     let p0 = Vec3::new(0.0, 0.0, 0.0);
     let p1 = Vec3::new(165.0, 165.0, 165.0);
 
-    world.add(Translate::new(Vec3::new(130.0, 0.0, 65.0),
-                             Rotate::new(-18.0, Rectangle::new(p0, p1, Arc::new(white.clone())))));
+    world.add(Transform::rotate(Vec3::unit_y(),
+                                -18.0,
+                                Rectangle::new(p0, p1, Arc::new(white.clone())))
+                  .then(Mat4::from_translation(Vec3::new(130.0, 0.0, 65.0))));
 
     let p2 = Vec3::new(165.0, 330.0, 165.0);
 
-    world.add(Translate::new(Vec3::new(265.0, 0.0, 295.0),
-                             Rotate::new(15.0, Rectangle::new(p0, p2, Arc::new(white.clone())))));
+    world.add(Transform::rotate(Vec3::unit_y(),
+                                15.0,
+                                Rectangle::new(p0, p2, Arc::new(white.clone())))
+                  .then(Mat4::from_translation(Vec3::new(265.0, 0.0, 295.0))));
 
     let bvh = BVH::new(&mut world.objects, 0.0, 1.0);
 
+    let placement = Placement::new(origin, lookat, view, time0, time1, atmosphere);
+    let camera =
+        Perspective::new(placement, fov, aspect_ratio, aperture, 1.0).with_autofocus(&bvh, &focus);
+
     let light = Light::new(ConstantTexture::new(0.0, 0.0, 0.0));
     let light_shape = Plane::new(Axis::XZ, 213.0, 343.0, 227.0, 332.0, 554.0, light);
 
-    (String::from("Cornell Box"), camera, bvh, light_shape)
+    (String::from("Cornell Box"), Box::new(camera), bvh, light_shape)
 }
 
-pub fn spheres_in_box_scene(width: usize, height: usize) -> (String, Camera, BVH, Plane) {
+pub fn spheres_in_box_scene(width: usize, height: usize) -> (String, Box<dyn Camera>, BVH, Plane) {
     let origin = Vec3::new(478.0, 278.0, -600.0);
     let lookat = Vec3::new(278.0, 278.0, 0.0);
     let view = Vec3::new(0.0, 1.0, 0.0);
     let fov = 40.0;
-    let aspect_ratio = (width / height) as f32;
+    let aspect_ratio = width as f32 / height as f32;
     let aperture = 0.0;
     let focus_distance = 10.0;
     let time0 = 0.0;
     let time1 = 1.0;
     let atmosphere = false;
 
-    let camera = Camera::new(origin,
-                             lookat,
-                             view,
-                             fov,
-                             aspect_ratio,
-                             aperture,
-                             focus_distance,
-                             time0,
-                             time1,
-                             atmosphere);
+    let placement = Placement::new(origin, lookat, view, time0, time1, atmosphere);
+    let camera = Perspective::new(placement, fov, aspect_ratio, aperture, focus_distance);
 
     let mut world = World::new();
 
@@ -475,25 +581,23 @@ pub fn spheres_in_box_scene(width: usize, height: usize) -> (String, Camera, BVH
                           0.0,
                           1.0));
 
-    let boundary = Sphere::new(Vec3::new(360.0, 150.0, 145.0),
-                               Vec3::new(360.0, 150.0, 145.0),
-                               70.0,
-                               Refractive::new(1.5),
-                               0.0,
-                               1.0);
-
-    world.add(boundary.clone());
-
-    world.add(Volume::new(0.2, boundary.clone(), ConstantTexture::new(0.2, 0.4, 0.9)));
+    let albedo = Vec3::new(0.2, 0.4, 0.9);
+    let subsurface = Medium::homogeneous(0.2 * (Vec3::one() - albedo), 0.2 * albedo, 0.0);
+    world.add(Sphere::new(Vec3::new(360.0, 150.0, 145.0),
+                          Vec3::new(360.0, 150.0, 145.0),
+                          70.0,
+                          Interior::new(Refractive::new(1.5), subsurface),
+                          0.0,
+                          1.0));
 
     let fog = Sphere::new(Vec3::new(0.0, 0.0, 0.0),
                           Vec3::new(0.0, 0.0, 0.0),
                           5000.0,
-                          Refractive::new(1.5),
+                          Empty::new(),
                           0.0,
                           1.0);
 
-    world.add(Volume::new(0.0001, fog, ConstantTexture::new(1.0, 1.0, 1.0)));
+    world.add(Volume::new(fog, Medium::homogeneous(Vec3::zero(), Vec3::splat(0.0001), 0.0)));
 
     world.add(Sphere::new(Vec3::new(400.0, 200.0, 400.0),
                           Vec3::new(400.0, 200.0, 400.0),
@@ -517,7 +621,8 @@ pub fn spheres_in_box_scene(width: usize, height: usize) -> (String, Camera, BVH
 
         let sphere = Sphere::new(center, center, 10.0, white.clone(), 0.0, 1.0);
 
-        world.add(Translate::new(Vec3::new(-100.0, 270.0, 395.0), Rotate::new(15.0, sphere)));
+        world.add(Transform::rotate(Vec3::unit_y(), 15.0, sphere)
+                      .then(Mat4::from_translation(Vec3::new(-100.0, 270.0, 395.0))));
     }
 
     let bvh = BVH::new(&mut world.objects, 0.0, 1.0);
@@ -525,5 +630,5 @@ pub fn spheres_in_box_scene(width: usize, height: usize) -> (String, Camera, BVH
     let light = Light::new(ConstantTexture::new(0.0, 0.0, 0.0));
     let light_shape = Plane::new(Axis::XZ, 123.0, 423.0, 147.0, 412.0, 554.0, light);
 
-    (String::from("Spheres in Box"), camera, bvh, light_shape)
+    (String::from("Spheres in Box"), Box::new(camera), bvh, light_shape)
 }
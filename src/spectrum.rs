@@ -0,0 +1,267 @@
+use glam::Vec3;
+use rand::rngs::ThreadRng;
+use rand::Rng;
+
+/// The shortest wavelength in nanometers sampled in spectral mode
+pub const LAMBDA_MIN: f32 = 360.0;
+
+/// The longest wavelength in nanometers sampled in spectral mode
+pub const LAMBDA_MAX: f32 = 830.0;
+
+/// Integral of the CIE 1931 y color matching function over the visible range
+const CIE_Y_INTEGRAL: f32 = 106.856_895;
+
+/// Linear sRGB of the equal-energy white that an RGB of (1, 1, 1) upsamples to
+///
+/// The sRGB matrix maps the D65 white point to (1, 1, 1), but white
+/// reflectances upsample to a flat spectrum, illuminant E, which lands on
+/// this slightly pink color instead. Dividing each channel by it is a von
+/// Kries white balance from E to D65, so a white RGB round trips to white.
+const EQUAL_ENERGY_WHITE: [f32; 3] = [1.204_784_3, 0.948_300_8, 0.908_842_7];
+
+/// The number of bins in the Smits basis spectra
+const SMITS_BINS: usize = 10;
+
+const SMITS_WHITE: [f32; SMITS_BINS] =
+    [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const SMITS_CYAN: [f32; SMITS_BINS] =
+    [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const SMITS_MAGENTA: [f32; SMITS_BINS] =
+    [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const SMITS_YELLOW: [f32; SMITS_BINS] =
+    [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const SMITS_RED: [f32; SMITS_BINS] =
+    [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const SMITS_GREEN: [f32; SMITS_BINS] =
+    [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025];
+const SMITS_BLUE: [f32; SMITS_BINS] =
+    [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+
+/// Wavelengths carried by a single path in spectral mode
+///
+/// We use hero wavelength sampling: one wavelength is picked uniformly
+/// over the visible range and the other two are placed at equal offsets
+/// from it, wrapping around the range. This lets each path carry three
+/// spectral samples in the same Vec3 the RGB integrator uses.
+///
+/// Reference:
+/// Alexander Wilkie, Sehera Nawaz, Marc Droske, Andrea Weidlich, Johannes Hanika
+/// Hero Wavelength Spectral Sampling, Eurographics Symposium on Rendering 2014
+#[derive(Clone, Copy)]
+pub struct Wavelengths {
+    pub lambda: Vec3,
+    secondary_terminated: bool,
+}
+
+impl Wavelengths {
+    /// Sample a new set of wavelengths with a random hero wavelength
+    pub fn sample(rng: &mut ThreadRng) -> Wavelengths {
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        let hero = LAMBDA_MIN + rng.gen::<f32>() * range;
+
+        let rotate = |offset: f32| {
+            let lambda = hero + offset * range / 3.0;
+            if lambda > LAMBDA_MAX {
+                lambda - range
+            } else {
+                lambda
+            }
+        };
+
+        Wavelengths { lambda: Vec3::new(hero, rotate(1.0), rotate(2.0)),
+                      secondary_terminated: false }
+    }
+
+    /// The hero wavelength that drives wavelength-dependent scattering
+    pub fn hero(&self) -> f32 {
+        self.lambda.x()
+    }
+
+    /// Drop the secondary wavelengths after a wavelength-dependent event
+    ///
+    /// Once a dispersive interface bends the path for the hero wavelength,
+    /// the other wavelengths no longer follow the same path. The hero sample
+    /// is scaled so the estimator stays unbiased.
+    pub fn terminate_secondary(&mut self, radiance: &mut Vec3) {
+        if !self.secondary_terminated {
+            *radiance = Vec3::new(3.0 * radiance.x(), 0.0, 0.0);
+            self.secondary_terminated = true;
+        }
+    }
+
+    /// Sample the spectrum of an RGB color at each of the path's wavelengths
+    pub fn sample_rgb(&self, color: Vec3) -> Vec3 {
+        Vec3::new(rgb_to_spectrum(color, self.lambda.x()),
+                  rgb_to_spectrum(color, self.lambda.y()),
+                  rgb_to_spectrum(color, self.lambda.z()))
+    }
+
+    /// Convert the spectral radiance carried by a path to linear sRGB
+    pub fn to_rgb(self, radiance: Vec3) -> Vec3 {
+        let pdf = 1.0 / (LAMBDA_MAX - LAMBDA_MIN);
+
+        let mut xyz = Vec3::zero();
+        for (i, &lambda) in self.lambda.as_ref().iter().enumerate() {
+            xyz += radiance.as_ref()[i] * color_matching(lambda);
+        }
+        xyz /= 3.0 * pdf * CIE_Y_INTEGRAL;
+
+        xyz_to_rgb(xyz)
+    }
+}
+
+/// Evaluate Planck's law for the spectral radiance of a blackbody
+///
+/// The wavelength is in nanometers, the temperature in Kelvin and the
+/// radiance in W / (sr m^2 nm).
+pub fn blackbody(lambda: f32, temperature: f32) -> f32 {
+    if temperature <= 0.0 {
+        return 0.0;
+    }
+
+    let c = 299_792_458.0f64;
+    let h = 6.626_070_15e-34f64;
+    let kb = 1.380_649e-23f64;
+    let l = lambda as f64 * 1e-9;
+
+    let exponent = h * c / (l * kb * temperature as f64);
+    let radiance = (2.0 * h * c * c) / (l.powi(5) * (exponent.exp() - 1.0));
+    (radiance * 1e-9) as f32
+}
+
+/// Convert the radiance of a blackbody at the given temperature to linear sRGB
+///
+/// The spectrum is integrated against the color matching functions with
+/// the same normalization as to_rgb, so the result can be used directly
+/// as emitted radiance.
+pub fn blackbody_rgb(temperature: f32) -> Vec3 {
+    let step = 5.0;
+    let steps = ((LAMBDA_MAX - LAMBDA_MIN) / step) as usize;
+
+    let mut xyz = Vec3::zero();
+    for i in 0..steps {
+        let lambda = LAMBDA_MIN + (i as f32 + 0.5) * step;
+        xyz += blackbody(lambda, temperature) * step * color_matching(lambda);
+    }
+
+    xyz_to_rgb(xyz / CIE_Y_INTEGRAL)
+}
+
+/// A piecewise Gaussian used to fit the CIE color matching functions
+fn lobe(lambda: f32, mu: f32, sigma_low: f32, sigma_high: f32) -> f32 {
+    let sigma = if lambda < mu { sigma_low } else { sigma_high };
+    let t = (lambda - mu) / sigma;
+    (-0.5 * t * t).exp()
+}
+
+/// Evaluate the CIE 1931 XYZ color matching functions at the given wavelength
+///
+/// Reference:
+/// Chris Wyman, Peter-Pike Sloan, Peter Shirley
+/// Simple Analytic Approximations to the CIE XYZ Color Matching Functions
+/// Journal of Computer Graphics Techniques Vol. 2, No. 2, 2013
+pub fn color_matching(lambda: f32) -> Vec3 {
+    let x = 1.056 * lobe(lambda, 599.8, 37.9, 31.0) + 0.362 * lobe(lambda, 442.0, 16.0, 26.7)
+            - 0.065 * lobe(lambda, 501.1, 20.4, 26.2);
+    let y = 0.821 * lobe(lambda, 568.8, 46.9, 40.5) + 0.286 * lobe(lambda, 530.9, 16.3, 31.1);
+    let z = 1.217 * lobe(lambda, 437.0, 11.8, 36.0) + 0.681 * lobe(lambda, 459.0, 26.0, 13.8);
+
+    Vec3::new(x, y, z)
+}
+
+/// Convert CIE XYZ to white balanced linear sRGB
+///
+/// The film is balanced for an equal-energy white rather than D65, see
+/// EQUAL_ENERGY_WHITE.
+pub fn xyz_to_rgb(xyz: Vec3) -> Vec3 {
+    let r = 3.240_454_2 * xyz.x() - 1.537_138_5 * xyz.y() - 0.498_531_4 * xyz.z();
+    let g = -0.969_266 * xyz.x() + 1.876_010_8 * xyz.y() + 0.041_556 * xyz.z();
+    let b = 0.055_643_4 * xyz.x() - 0.204_025_9 * xyz.y() + 1.057_225_2 * xyz.z();
+
+    Vec3::new(r / EQUAL_ENERGY_WHITE[0],
+              g / EQUAL_ENERGY_WHITE[1],
+              b / EQUAL_ENERGY_WHITE[2])
+}
+
+/// Linearly interpolate one of the Smits basis spectra at the given wavelength
+fn smits_basis(basis: &[f32; SMITS_BINS], lambda: f32) -> f32 {
+    let (start, end) = (380.0, 720.0);
+    let bin_width = (end - start) / SMITS_BINS as f32;
+    let position = ((lambda - start) / bin_width - 0.5).max(0.0).min((SMITS_BINS - 1) as f32);
+
+    let i = (position as usize).min(SMITS_BINS - 2);
+    let t = position - i as f32;
+
+    (1.0 - t) * basis[i] + t * basis[i + 1]
+}
+
+/// Upsample an RGB color to a smooth spectrum and evaluate it at the given wavelength
+///
+/// The spectrum is built from white, cyan, magenta, yellow, red, green,
+/// and blue basis spectra so that colors within the unit cube map to
+/// plausible reflectances. The construction is linear in the input, so
+/// emission values above one are upsampled the same way.
+///
+/// Reference:
+/// Brian Smits
+/// An RGB to Spectrum Conversion for Reflectances
+/// Journal of Graphics Tools Vol. 4 Issue 4, 1999
+pub fn rgb_to_spectrum(color: Vec3, lambda: f32) -> f32 {
+    let (r, g, b) = (color.x(), color.y(), color.z());
+    let basis = |spectrum: &[f32; SMITS_BINS]| smits_basis(spectrum, lambda);
+
+    if r <= g && r <= b {
+        let white = r * basis(&SMITS_WHITE);
+        if g <= b {
+            white + (g - r) * basis(&SMITS_CYAN) + (b - g) * basis(&SMITS_BLUE)
+        } else {
+            white + (b - r) * basis(&SMITS_CYAN) + (g - b) * basis(&SMITS_GREEN)
+        }
+    } else if g <= r && g <= b {
+        let white = g * basis(&SMITS_WHITE);
+        if r <= b {
+            white + (r - g) * basis(&SMITS_MAGENTA) + (b - r) * basis(&SMITS_BLUE)
+        } else {
+            white + (b - g) * basis(&SMITS_MAGENTA) + (r - b) * basis(&SMITS_RED)
+        }
+    } else {
+        let white = b * basis(&SMITS_WHITE);
+        if r <= g {
+            white + (r - b) * basis(&SMITS_YELLOW) + (g - r) * basis(&SMITS_GREEN)
+        } else {
+            white + (g - b) * basis(&SMITS_YELLOW) + (r - g) * basis(&SMITS_RED)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_white_round_trip() {
+        let white = Vec3::one();
+        let mut xyz = Vec3::zero();
+
+        let steps = 470;
+        for i in 0..steps {
+            let lambda = LAMBDA_MIN + (i as f32 + 0.5);
+            xyz += rgb_to_spectrum(white, lambda) * color_matching(lambda);
+        }
+        let rgb = xyz_to_rgb(xyz / CIE_Y_INTEGRAL);
+
+        assert!((rgb - white).abs().max_element() < 0.02);
+    }
+
+    #[test]
+    fn test_blackbody_gets_bluer_with_temperature() {
+        let ratio = |temperature: f32| {
+            let rgb = blackbody_rgb(temperature);
+            rgb.z() / rgb.x()
+        };
+
+        assert!(ratio(1500.0) < ratio(3000.0));
+        assert!(ratio(3000.0) < ratio(6500.0));
+        assert!(blackbody_rgb(3000.0).x() > blackbody_rgb(1500.0).x());
+    }
+}
@@ -55,6 +55,24 @@ fn get_sphere_uv(p: &Vec3) -> (f32, f32) {
     (u, v)
 }
 
+/// Compute the partial derivatives of a point on the unit sphere with respect to u and v
+///
+/// These follow from differentiating the spherical coordinates used by
+/// get_sphere_uv. Near the poles the u derivative vanishes, so we fall
+/// back to a tangent that keeps the frame well defined.
+fn get_sphere_derivatives(p: &Vec3, radius: f32) -> (Vec3, Vec3) {
+    let rho = (p.x() * p.x() + p.z() * p.z()).sqrt();
+
+    if rho < 1e-6 {
+        return (Vec3::new(2.0 * PI * radius, 0.0, 0.0), Vec3::new(0.0, 0.0, PI * radius));
+    }
+
+    let dpdu = 2.0 * PI * radius * Vec3::new(p.z(), 0.0, -p.x());
+    let dpdv = PI * radius * Vec3::new(-p.y() * p.x() / rho, rho, -p.y() * p.z() / rho);
+
+    (dpdu, dpdv)
+}
+
 impl Hitable for Sphere {
     /// Determine if the given ray intersects with a point on the sphere
     ///
@@ -80,13 +98,18 @@ impl Hitable for Sphere {
                     let point = ray.point_at_parameter(root);
                     let normal = (point - self.center(ray.time)) / self.radius;
                     let (u, v) = get_sphere_uv(&normal);
-                    return Some(HitRecord::new(root,
-                                               u,
-                                               v,
-                                               point,
-                                               normal,
-                                               normal,
-                                               self.material.clone()));
+                    let (dpdu, dpdv) = get_sphere_derivatives(&normal, self.radius);
+                    let record = HitRecord::new(root,
+                                                u,
+                                                v,
+                                                point,
+                                                normal,
+                                                normal,
+                                                self.material.clone()).with_derivatives(dpdu,
+                                                                                        dpdv);
+                    if record.is_opaque(ray) {
+                        return Some(record);
+                    }
                 }
             }
         }
@@ -1,9 +1,34 @@
-use glam::Vec3;
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use glam::{Vec2, Vec3};
 use image;
+use image::hdr::HDRDecoder;
+use image::png::PNGDecoder;
+use image::{ColorType, ImageDecoder};
+
+use noise;
+use noise::{Perlin, Worley};
 
 /// Texture trait can be implemented so that textures can be applied to materials
 pub trait Texture: Send + Sync {
-    fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3;
+    /// Retrieve the color of the texture at a point
+    ///
+    /// footprint is the width of the lookup in texture coordinates, which
+    /// filtered textures use to avoid aliasing. A footprint of zero asks
+    /// for the finest detail.
+    fn value(&self, u: f32, v: f32, p: &Vec3, footprint: f32) -> Vec3;
+
+    /// Retrieve a scalar from the texture for driving material parameters
+    ///
+    /// Single-channel images and procedural textures store the same value
+    /// in every channel, so the first channel is used.
+    fn scalar(&self, u: f32, v: f32, p: &Vec3, footprint: f32) -> f32 {
+        self.value(u, v, p, footprint).x()
+    }
 }
 
 #[derive(Clone)]
@@ -17,39 +42,626 @@ impl ConstantTexture {
     pub fn new(r: f32, g: f32, b: f32) -> ConstantTexture {
         ConstantTexture { color: Vec3::new(r, g, b) }
     }
+
+    /// Create a new ConstantTexture with the same value in every channel
+    pub fn splat(value: f32) -> ConstantTexture {
+        ConstantTexture { color: Vec3::splat(value) }
+    }
 }
 
 /// Implement the Texture trait for ConstantTexture
 /// This allows the ConstantTexture's color to be retrieved
 /// as well as the ConstantTexture to be cloned.
 impl Texture for ConstantTexture {
-    fn value(&self, _u: f32, _v: f32, _p: &Vec3) -> Vec3 {
+    fn value(&self, _u: f32, _v: f32, _p: &Vec3, _footprint: f32) -> Vec3 {
         self.color
     }
 }
 
+#[derive(Clone)]
+/// ChannelTexture selects a single channel of another texture
+///
+/// This unpacks maps that store several material parameters in one image,
+/// such as occlusion, roughness, and metalness in red, green, and blue.
+pub struct ChannelTexture {
+    texture: Arc<dyn Texture>,
+    channel: usize,
+}
+
+impl ChannelTexture {
+    /// Create a new ChannelTexture where channel 0, 1, and 2 select red, green, and blue
+    pub fn new<T: Texture + 'static>(texture: T, channel: usize) -> ChannelTexture {
+        assert!(channel < 3, "ChannelTexture channel must be 0, 1, or 2");
+        let texture = Arc::new(texture);
+        ChannelTexture { texture, channel }
+    }
+}
+
+impl Texture for ChannelTexture {
+    fn value(&self, u: f32, v: f32, p: &Vec3, footprint: f32) -> Vec3 {
+        Vec3::splat(self.texture.value(u, v, p, footprint).as_ref()[self.channel])
+    }
+}
+
+#[derive(Clone, Copy)]
+/// Filter determines how texels are reconstructed between texel centers
+pub enum Filter {
+    /// Use the texel that contains the lookup point
+    Nearest,
+    /// Blend the four texels around the lookup point
+    Bilinear,
+    /// Blend bilinear lookups from the two mip levels bracketing the lookup's footprint
+    ///
+    /// The footprint is the width of the lookup in texture coordinates,
+    /// so a footprint of 1 / 256 selects the 256 texel wide level.
+    Trilinear,
+}
+
+#[derive(Clone, Copy)]
+/// Wrap determines how texture coordinates outside of [0, 1] are handled
+pub enum Wrap {
+    /// Tile the image
+    Repeat,
+    /// Tile the image, flipping every other tile
+    Mirror,
+    /// Stretch the border texels
+    Clamp,
+}
+
+#[derive(Clone)]
+/// One level of an image pyramid stored as linear RGB
+struct MipLevel {
+    width: usize,
+    height: usize,
+    texels: Vec<Vec3>,
+}
+
+impl MipLevel {
+    /// Downsample the level by averaging each two by two block of texels
+    fn downsample(&self) -> MipLevel {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+
+        let mut texels = Vec::with_capacity(width * height);
+        for j in 0..height {
+            for i in 0..width {
+                let (x0, y0) = ((2 * i).min(self.width - 1), (2 * j).min(self.height - 1));
+                let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+
+                let sum = self.texels[y0 * self.width + x0]
+                          + self.texels[y0 * self.width + x1]
+                          + self.texels[y1 * self.width + x0]
+                          + self.texels[y1 * self.width + x1];
+                texels.push(0.25 * sum);
+            }
+        }
+
+        MipLevel { width,
+                   height,
+                   texels }
+    }
+}
+
+/// Convert an sRGB encoded value in [0, 1] to linear
+///
+/// Reference: https://en.wikipedia.org/wiki/SRGB#The_reverse_transformation
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Apply a wrap mode to an integer texel coordinate
+fn wrap_texel(i: i64, size: usize, wrap: Wrap) -> usize {
+    let size = size as i64;
+    match wrap {
+        Wrap::Repeat => i.rem_euclid(size) as usize,
+        Wrap::Clamp => i.max(0).min(size - 1) as usize,
+        Wrap::Mirror => {
+            let period = i.rem_euclid(2 * size);
+            if period < size {
+                period as usize
+            } else {
+                (2 * size - 1 - period) as usize
+            }
+        }
+    }
+}
+
+/// Load an image file into a vector of linear RGB texels with the bottom row first
+///
+/// Radiance HDR files are read as floats and 16-bit PNG files keep their full
+/// precision. All other formats are read as 8-bit RGB. When srgb is set, the
+/// low dynamic range formats are decoded from sRGB to linear.
+fn load_texels(filename: &str, srgb: bool) -> MipLevel {
+    let decode = |value: f32| if srgb { srgb_to_linear(value) } else { value };
+    let extension = Path::new(filename).extension()
+                                       .and_then(|extension| extension.to_str())
+                                       .map(|extension| extension.to_lowercase());
+
+    let (width, height, mut texels): (usize, usize, Vec<Vec3>) = match extension.as_deref() {
+        Some("hdr") => {
+            let reader = BufReader::new(File::open(filename).unwrap());
+            let decoder = HDRDecoder::new(reader).unwrap();
+            let metadata = decoder.metadata();
+            let texels = decoder.read_image_hdr()
+                                .unwrap()
+                                .iter()
+                                .map(|&image::Rgb([r, g, b])| Vec3::new(r, g, b))
+                                .collect();
+            (metadata.width as usize, metadata.height as usize, texels)
+        }
+        Some("png") if sixteen_bit_png(filename) => {
+            let decoder = PNGDecoder::new(File::open(filename).unwrap()).unwrap();
+            let (width, height) = decoder.dimensions();
+            let channels = match decoder.colortype() {
+                ColorType::Gray(_) => 1,
+                ColorType::GrayA(_) => 2,
+                ColorType::RGB(_) => 3,
+                _ => 4,
+            };
+            let samples: Vec<f32> = decoder.read_image()
+                                           .unwrap()
+                                           .chunks(2)
+                                           .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+                                           .map(|sample| decode(sample as f32 / 65535.0))
+                                           .collect();
+            let texels = samples.chunks(channels)
+                                .map(|texel| {
+                                    if channels < 3 {
+                                        Vec3::splat(texel[0])
+                                    } else {
+                                        Vec3::new(texel[0], texel[1], texel[2])
+                                    }
+                                })
+                                .collect();
+            (width as usize, height as usize, texels)
+        }
+        _ => {
+            let image = image::open(filename).unwrap().to_rgb();
+            let texels =
+                image.pixels()
+                     .map(|&image::Rgb([r, g, b])| {
+                         Vec3::new(decode(r as f32 / 255.0),
+                                   decode(g as f32 / 255.0),
+                                   decode(b as f32 / 255.0))
+                     })
+                     .collect();
+            (image.width() as usize, image.height() as usize, texels)
+        }
+    };
+
+    let mut flipped = Vec::with_capacity(texels.len());
+    for row in texels.chunks_mut(width).rev() {
+        flipped.extend_from_slice(row);
+    }
+
+    MipLevel { width,
+               height,
+               texels: flipped }
+}
+
+/// Check whether the PNG file stores 16 bits per channel
+fn sixteen_bit_png(filename: &str) -> bool {
+    let decoder = PNGDecoder::new(File::open(filename).unwrap()).unwrap();
+    matches!(decoder.colortype(),
+             ColorType::Gray(16) | ColorType::GrayA(16) | ColorType::RGB(16) | ColorType::RGBA(16))
+}
+
 #[derive(Clone)]
 /// ImageTexture is a struct for textures loaded from file
 pub struct ImageTexture {
-    im: image::RgbImage,
+    levels: Vec<MipLevel>,
+    filter: Filter,
+    wrap: Wrap,
+    scale: Vec2,
+    offset: Vec2,
+    sin_rotation: f32,
+    cos_rotation: f32,
 }
 
 /// Create a new texture from the given data and image dimensions
 impl ImageTexture {
+    /// Load a color texture whose 8-bit and 16-bit images are sRGB encoded
+    ///
+    /// Textures are filtered bilinearly and repeat by default.
     pub fn new(filename: &str) -> ImageTexture {
-        ImageTexture { im: image::open(filename).unwrap().flipv().to_rgb() }
+        ImageTexture::from_level(load_texels(filename, true))
+    }
+
+    /// Load a data texture such as a normal or roughness map without sRGB decoding
+    pub fn linear(filename: &str) -> ImageTexture {
+        ImageTexture::from_level(load_texels(filename, false))
+    }
+
+    fn from_level(level: MipLevel) -> ImageTexture {
+        ImageTexture { levels: vec![level],
+                       filter: Filter::Bilinear,
+                       wrap: Wrap::Repeat,
+                       scale: Vec2::new(1.0, 1.0),
+                       offset: Vec2::new(0.0, 0.0),
+                       sin_rotation: 0.0,
+                       cos_rotation: 1.0 }
+    }
+
+    /// Set the reconstruction filter, building the mip pyramid if it is needed
+    pub fn with_filter(mut self, filter: Filter) -> ImageTexture {
+        if let Filter::Trilinear = filter {
+            while let Some(next) = self.levels
+                                       .last()
+                                       .filter(|level| level.width > 1 || level.height > 1)
+                                       .map(MipLevel::downsample)
+            {
+                self.levels.push(next);
+            }
+        }
+        self.filter = filter;
+        self
+    }
+
+    /// Set how texture coordinates outside of [0, 1] are handled
+    pub fn with_wrap(mut self, wrap: Wrap) -> ImageTexture {
+        self.wrap = wrap;
+        self
+    }
+
+    /// Transform the texture coordinates before the lookup
+    ///
+    /// The coordinates are scaled, then rotated counterclockwise by
+    /// the angle in degrees, and then offset.
+    pub fn with_transform(mut self, scale: Vec2, offset: Vec2, rotation: f32) -> ImageTexture {
+        let radians = rotation * PI / 180.0;
+        self.scale = scale;
+        self.offset = offset;
+        self.sin_rotation = radians.sin();
+        self.cos_rotation = radians.cos();
+        self
+    }
+
+    fn texel(&self, level: &MipLevel, i: i64, j: i64) -> Vec3 {
+        let x = wrap_texel(i, level.width, self.wrap);
+        let y = wrap_texel(j, level.height, self.wrap);
+        level.texels[y * level.width + x]
+    }
+
+    fn nearest(&self, level: &MipLevel, u: f32, v: f32) -> Vec3 {
+        let i = (u * level.width as f32).floor() as i64;
+        let j = (v * level.height as f32).floor() as i64;
+        self.texel(level, i, j)
+    }
+
+    fn bilinear(&self, level: &MipLevel, u: f32, v: f32) -> Vec3 {
+        let x = u * level.width as f32 - 0.5;
+        let y = v * level.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (dx, dy) = (x - x0, y - y0);
+        let (i, j) = (x0 as i64, y0 as i64);
+
+        (1.0 - dx) * (1.0 - dy) * self.texel(level, i, j)
+        + dx * (1.0 - dy) * self.texel(level, i + 1, j)
+        + (1.0 - dx) * dy * self.texel(level, i, j + 1)
+        + dx * dy * self.texel(level, i + 1, j + 1)
+    }
+
+    fn trilinear(&self, u: f32, v: f32, footprint: f32) -> Vec3 {
+        let coarsest = self.levels.len() - 1;
+        let resolution = self.levels[0].width.max(self.levels[0].height) as f32;
+        let level = (footprint * resolution).max(1.0).log2().min(coarsest as f32);
+
+        let below = level.floor() as usize;
+        let above = (below + 1).min(coarsest);
+        let t = level - below as f32;
+
+        (1.0 - t) * self.bilinear(&self.levels[below], u, v)
+        + t * self.bilinear(&self.levels[above], u, v)
     }
 }
 
 /// Determine which pixel to retrieve from the image by
 /// converting pixel coordinates to UV coordinates
 impl Texture for ImageTexture {
-    fn value(&self, u: f32, v: f32, _p: &Vec3) -> Vec3 {
-        let i = 0.0f32.max((u * self.im.width() as f32).min(self.im.width() as f32 - 1.0));
-        let j = 0.0f32.max((v * self.im.height() as f32).min(self.im.height() as f32 - 1.0));
+    fn value(&self, u: f32, v: f32, _p: &Vec3, footprint: f32) -> Vec3 {
+        let (su, sv) = (u * self.scale.x(), v * self.scale.y());
+        let u = self.cos_rotation * su - self.sin_rotation * sv + self.offset.x();
+        let v = self.sin_rotation * su + self.cos_rotation * sv + self.offset.y();
+
+        match self.filter {
+            Filter::Nearest => self.nearest(&self.levels[0], u, v),
+            Filter::Bilinear => self.bilinear(&self.levels[0], u, v),
+            Filter::Trilinear => {
+                let scale = self.scale.x().abs().max(self.scale.y().abs());
+                self.trilinear(u, v, scale * footprint)
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+/// NoiseTexture is a solid texture of smooth gradient noise
+pub struct NoiseTexture {
+    perlin: Perlin,
+    scale: f32,
+}
+
+impl NoiseTexture {
+    pub fn new(scale: f32, seed: u64) -> NoiseTexture {
+        NoiseTexture { perlin: Perlin::new(seed),
+                       scale }
+    }
+}
+
+/// Remap the noise from [-1, 1] to a gray value in [0, 1]
+impl Texture for NoiseTexture {
+    fn value(&self, _u: f32, _v: f32, p: &Vec3, _footprint: f32) -> Vec3 {
+        Vec3::splat(0.5 * (1.0 + self.perlin.noise(&(self.scale * *p))))
+    }
+}
+
+#[derive(Clone)]
+/// TurbulenceTexture is a solid texture of fractal turbulence
+pub struct TurbulenceTexture {
+    perlin: Perlin,
+    scale: f32,
+    octaves: u32,
+}
+
+impl TurbulenceTexture {
+    pub fn new(scale: f32, octaves: u32, seed: u64) -> TurbulenceTexture {
+        TurbulenceTexture { perlin: Perlin::new(seed),
+                            scale,
+                            octaves }
+    }
+}
+
+impl Texture for TurbulenceTexture {
+    fn value(&self, _u: f32, _v: f32, p: &Vec3, _footprint: f32) -> Vec3 {
+        Vec3::splat(self.perlin.turbulence(&(self.scale * *p), self.octaves).min(1.0))
+    }
+}
+
+#[derive(Clone)]
+/// MarbleTexture is a solid texture of veins distorted by turbulence
+pub struct MarbleTexture {
+    perlin: Perlin,
+    scale: f32,
+    distortion: f32,
+}
+
+impl MarbleTexture {
+    /// Create a new MarbleTexture
+    ///
+    /// scale controls the frequency of the veins and distortion
+    /// controls how strongly turbulence bends them.
+    pub fn new(scale: f32, distortion: f32, seed: u64) -> MarbleTexture {
+        MarbleTexture { perlin: Perlin::new(seed),
+                        scale,
+                        distortion }
+    }
+}
+
+/// Veins run along planes of constant z and are displaced by turbulence
+///
+/// See Chapter 5 in Peter Shirley's Ray Tracing: The Next Week.
+impl Texture for MarbleTexture {
+    fn value(&self, _u: f32, _v: f32, p: &Vec3, _footprint: f32) -> Vec3 {
+        let point = self.scale * *p;
+        let turbulence = self.perlin.turbulence(&point, 7);
+        Vec3::splat(0.5 * (1.0 + (point.z() + self.distortion * turbulence).sin()))
+    }
+}
+
+#[derive(Clone)]
+/// WoodTexture is a solid texture of concentric growth rings around the y axis
+pub struct WoodTexture {
+    perlin: Perlin,
+    scale: f32,
+    distortion: f32,
+}
+
+impl WoodTexture {
+    /// Create a new WoodTexture
+    ///
+    /// scale controls the number of rings per unit and distortion
+    /// controls how much noise wobbles the rings.
+    pub fn new(scale: f32, distortion: f32, seed: u64) -> WoodTexture {
+        WoodTexture { perlin: Perlin::new(seed),
+                      scale,
+                      distortion }
+    }
+}
+
+impl Texture for WoodTexture {
+    fn value(&self, _u: f32, _v: f32, p: &Vec3, _footprint: f32) -> Vec3 {
+        let point = self.scale * *p;
+        let radius = (point.x() * point.x() + point.z() * point.z()).sqrt();
+        let rings = radius + self.distortion * self.perlin.fbm(&point, 4);
+        Vec3::splat(rings - rings.floor())
+    }
+}
+
+#[derive(Clone)]
+/// The quantity of cellular noise that a VoronoiTexture returns
+pub enum VoronoiMode {
+    /// The distance to the nearest feature point
+    Distance,
+    /// The difference between the two nearest distances, which highlights cell borders
+    Border,
+    /// A random gray value that is constant across each cell
+    Cells,
+}
+
+#[derive(Clone)]
+/// VoronoiTexture is a solid texture of Worley cellular noise
+pub struct VoronoiTexture {
+    worley: Worley,
+    scale: f32,
+    mode: VoronoiMode,
+}
+
+impl VoronoiTexture {
+    pub fn new(scale: f32, mode: VoronoiMode, seed: u64) -> VoronoiTexture {
+        VoronoiTexture { worley: Worley::new(seed),
+                         scale,
+                         mode }
+    }
+}
+
+impl Texture for VoronoiTexture {
+    fn value(&self, _u: f32, _v: f32, p: &Vec3, _footprint: f32) -> Vec3 {
+        let sample = self.worley.sample(&(self.scale * *p));
+
+        match self.mode {
+            VoronoiMode::Distance => Vec3::splat(sample.f1.min(1.0)),
+            VoronoiMode::Border => Vec3::splat((sample.f2 - sample.f1).min(1.0)),
+            VoronoiMode::Cells => Vec3::splat(noise::to_unit(noise::hash(sample.cell))),
+        }
+    }
+}
+
+#[derive(Clone)]
+/// The space in which a CheckerTexture lays out its squares
+pub enum CheckerSpace {
+    /// Squares tile the surface's texture coordinates
+    UV,
+    /// Cubes fill space so the pattern is independent of parameterization
+    Solid,
+}
+
+#[derive(Clone)]
+/// CheckerTexture alternates between two textures in a checkerboard pattern
+pub struct CheckerTexture {
+    odd: Arc<dyn Texture>,
+    even: Arc<dyn Texture>,
+    scale: f32,
+    space: CheckerSpace,
+}
+
+impl CheckerTexture {
+    /// Create a new CheckerTexture
+    ///
+    /// scale is the number of squares per unit of texture coordinates
+    /// or world space depending on the space of the checkerboard.
+    pub fn new<T: Texture + 'static, S: Texture + 'static>(odd: T,
+                                                           even: S,
+                                                           scale: f32,
+                                                           space: CheckerSpace)
+                                                           -> CheckerTexture {
+        let odd = Arc::new(odd);
+        let even = Arc::new(even);
+        CheckerTexture { odd,
+                         even,
+                         scale,
+                         space }
+    }
+}
+
+impl Texture for CheckerTexture {
+    fn value(&self, u: f32, v: f32, p: &Vec3, footprint: f32) -> Vec3 {
+        let sum = match self.space {
+            CheckerSpace::UV => (self.scale * u).floor() + (self.scale * v).floor(),
+            CheckerSpace::Solid => {
+                (self.scale * p.x()).floor()
+                + (self.scale * p.y()).floor()
+                + (self.scale * p.z()).floor()
+            }
+        };
+
+        if sum as i64 % 2 == 0 {
+            self.even.value(u, v, p, footprint)
+        } else {
+            self.odd.value(u, v, p, footprint)
+        }
+    }
+}
+
+#[derive(Clone)]
+/// ColorRamp maps the first channel of a texture through a color gradient
+pub struct ColorRamp {
+    input: Arc<dyn Texture>,
+    stops: Vec<(f32, Vec3)>,
+}
+
+impl ColorRamp {
+    /// Create a new ColorRamp from the input texture and the gradient's stops
+    ///
+    /// Each stop is a position in [0, 1] and the color at that position.
+    /// Values between stops are linearly interpolated and values outside
+    /// of the stops take the color of the nearest stop.
+    pub fn new<T: Texture + 'static>(input: T, mut stops: Vec<(f32, Vec3)>) -> ColorRamp {
+        let input = Arc::new(input);
+        stops.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        ColorRamp { input, stops }
+    }
+}
+
+impl Texture for ColorRamp {
+    fn value(&self, u: f32, v: f32, p: &Vec3, footprint: f32) -> Vec3 {
+        let t = self.input.value(u, v, p, footprint).x();
+
+        match self.stops.iter().position(|&(position, _)| position > t) {
+            None => self.stops.last().map_or(Vec3::zero(), |&(_, color)| color),
+            Some(0) => self.stops[0].1,
+            Some(i) => {
+                let (start, low) = self.stops[i - 1];
+                let (end, high) = self.stops[i];
+                low.lerp(high, (t - start) / (end - start))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hitable::HitRecord;
+    use materials::Empty;
+    use ray::Ray;
+
+    #[test]
+    fn test_wrap_modes() {
+        let size = 4;
+
+        assert_eq!(wrap_texel(5, size, Wrap::Repeat), 1);
+        assert_eq!(wrap_texel(-1, size, Wrap::Repeat), 3);
+        assert_eq!(wrap_texel(5, size, Wrap::Mirror), 2);
+        assert_eq!(wrap_texel(-1, size, Wrap::Mirror), 0);
+        assert_eq!(wrap_texel(5, size, Wrap::Clamp), 3);
+        assert_eq!(wrap_texel(-1, size, Wrap::Clamp), 0);
+    }
+
+    #[test]
+    fn test_trilinear_level_follows_footprint() {
+        // white on the left half and black on the right, averaging to gray at 1 x 1
+        let texels = (0..16).map(|i| if i % 4 < 2 { Vec3::one() } else { Vec3::zero() });
+        let level = MipLevel { width: 4,
+                               height: 4,
+                               texels: texels.collect() };
+        let texture = ImageTexture::from_level(level).with_filter(Filter::Trilinear);
+        let p = Vec3::zero();
+
+        assert!((texture.value(0.125, 0.5, &p, 0.0) - Vec3::one()).length() < 1e-4);
+        assert!((texture.value(0.125, 0.5, &p, 1.0) - Vec3::splat(0.5)).length() < 1e-4);
+        assert!((texture.value(0.125, 0.5, &p, 100.0) - Vec3::splat(0.5)).length() < 1e-4);
 
-        let image::Rgb([r, g, b]) = *self.im.get_pixel(i as u32, j as u32);
+        let constant = MipLevel { width: 4,
+                                  height: 4,
+                                  texels: vec![Vec3::splat(0.3); 16] };
+        let texture = ImageTexture::from_level(constant).with_filter(Filter::Trilinear);
+        for &footprint in &[0.0, 0.1, 0.3, 0.7, 1.0] {
+            let value = texture.value(0.4, 0.7, &p, footprint);
+            assert!((value - Vec3::splat(0.3)).length() < 1e-4);
+        }
 
-        Vec3::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0)
+        // a cone 0.1 wide at the hit covers 0.05 of a texture stretched over 2 units
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 10.0), Vec3::new(0.0, 0.0, -1.0), 0.0)
+            .with_cone(0.0, 0.01);
+        let normal = Vec3::new(0.0, 0.0, 1.0);
+        let material = Arc::new(Empty::new());
+        let hit = HitRecord::new(10.0, 0.5, 0.5, Vec3::zero(), normal, normal, material)
+            .with_derivatives(Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 2.0, 0.0))
+            .with_footprint(&ray);
+        assert!((hit.footprint - 0.05).abs() < 1e-4);
     }
 }
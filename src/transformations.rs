@@ -1,161 +1,360 @@
 use std::f32;
-use std::f32::consts::PI;
 use std::sync::Arc;
 
-use glam::Vec3;
+use glam::{Mat4, Quat, Vec3, Vec4};
 
 use aabb::AABB;
 use hitable::{HitRecord, Hitable};
 use ray::Ray;
 
-pub struct Translate {
-    offset: Vec3,
+/// The number of times each keyframe interval is sampled when bounding its motion
+const MOTION_BOUND_SAMPLES: usize = 16;
+
+/// Transform places a hitable in the scene with an arbitrary affine matrix
+///
+/// Rays are mapped into the object space of the hitable and hits are mapped
+/// back, with normals transformed by the inverse transpose so that they stay
+/// perpendicular to the surface under non-uniform scales and shears.
+/// Transforms are composed with then, so
+/// `Transform::scale(s, h).then(Mat4::from_translation(t))` scales first and
+/// translates second. Animated transforms are evaluated at the time of each
+/// ray before the static matrix is applied, which blurs moving objects.
+pub struct Transform {
     hitable: Arc<dyn Hitable>,
+    transform: Affine,
+    animation: Option<AnimatedTransform>,
 }
 
-impl Translate {
-    pub fn new<H: Hitable + 'static>(offset: Vec3, hitable: H) -> Translate {
-        let hitable = Arc::new(hitable);
-        Translate { offset, hitable }
+impl Transform {
+    /// Create a new Transform from the object to world matrix
+    pub fn new<H: Hitable + 'static>(matrix: Mat4, hitable: H) -> Transform {
+        Transform { hitable: Arc::new(hitable),
+                    transform: Affine::new(matrix),
+                    animation: None }
+    }
+
+    /// Create a new Transform that moves the hitable through the keyframes
+    pub fn animated<H: Hitable + 'static>(animation: AnimatedTransform, hitable: H) -> Transform {
+        Transform { hitable: Arc::new(hitable),
+                    transform: Affine::new(Mat4::identity()),
+                    animation: Some(animation) }
+    }
+
+    pub fn translate<H: Hitable + 'static>(offset: Vec3, hitable: H) -> Transform {
+        Transform::new(Mat4::from_translation(offset), hitable)
+    }
+
+    /// Rotate the hitable by the angle in degrees around the axis through the origin
+    pub fn rotate<H: Hitable + 'static>(axis: Vec3, angle: f32, hitable: H) -> Transform {
+        Transform::new(Mat4::from_axis_angle(axis.normalize(), angle.to_radians()), hitable)
+    }
+
+    pub fn quaternion<H: Hitable + 'static>(rotation: Quat, hitable: H) -> Transform {
+        Transform::new(Mat4::from_quat(rotation.normalize()), hitable)
+    }
+
+    /// Scale the hitable by a possibly different factor along each axis
+    pub fn scale<H: Hitable + 'static>(scale: Vec3, hitable: H) -> Transform {
+        Transform::new(Mat4::from_scale(scale), hitable)
+    }
+
+    /// Move the hitable to position and turn its -Z axis toward target
+    ///
+    /// The +Y axis of the hitable is turned as close to up as possible.
+    pub fn look_at<H: Hitable + 'static>(position: Vec3,
+                                         target: Vec3,
+                                         up: Vec3,
+                                         hitable: H)
+                                         -> Transform {
+        Transform::new(Mat4::look_at_rh(position, target, up).inverse(), hitable)
+    }
+
+    /// Apply another transformation after this one
+    pub fn then(self, matrix: Mat4) -> Transform {
+        Transform { hitable: self.hitable,
+                    transform: Affine::new(matrix * self.transform.matrix),
+                    animation: self.animation }
     }
 }
 
-impl Hitable for Translate {
+impl Hitable for Transform {
     fn hit(&self, ray: &Ray, position_min: f32, position_max: f32) -> Option<HitRecord> {
-        let moved_ray = Ray::new(ray.origin - self.offset, ray.direction, ray.time);
-        if let Some(mut hit) = self.hitable.hit(&moved_ray, position_min, position_max) {
-            hit.point += self.offset;
-            Some(hit)
-        } else {
-            None
-        }
+        let animated;
+        let transform = match &self.animation {
+            Some(animation) => {
+                animated = Affine::new(self.transform.matrix * animation.matrix(ray.time));
+                &animated
+            }
+            None => &self.transform,
+        };
+
+        let (object_ray, scale) = transform.ray_to_object(ray);
+        let hit = self.hitable
+                      .hit(&object_ray, position_min * scale, position_max * scale)?;
+        Some(transform.hit_to_world(hit, scale))
     }
 
     fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
-        if let Some(mut bbox) = self.hitable.bounding_box(t0, t1) {
-            bbox.minimum += self.offset;
-            bbox.maximum += self.offset;
-            Some(bbox)
-        } else {
-            None
-        }
+        let bbox = self.hitable.bounding_box(t0, t1)?;
+        let bbox = match &self.animation {
+            Some(animation) => animation.bounding_box(&bbox, t0, t1),
+            None => bbox,
+        };
+        Some(self.transform.bounding_box(&bbox))
     }
 }
 
-#[derive(Clone)]
-pub struct Rotate {
-    sin_theta: f32,
-    cos_theta: f32,
-    hitable: Arc<dyn Hitable>,
+/// A translation, rotation and scale at a point in time
+#[derive(Clone, Copy)]
+pub struct Keyframe {
+    pub time: f32,
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
 }
 
-impl Rotate {
-    pub fn new<H: Hitable + 'static>(angle: f32, hitable: H) -> Rotate {
-        let hitable = Arc::new(hitable);
-        let radians = (PI / 180.0) * angle;
-        let sin_theta = radians.sin();
-        let cos_theta = radians.cos();
-        Rotate { sin_theta,
-                 cos_theta,
-                 hitable }
+impl Keyframe {
+    pub fn new(time: f32, translation: Vec3, rotation: Quat, scale: Vec3) -> Keyframe {
+        Keyframe { time,
+                   translation,
+                   rotation: rotation.normalize(),
+                   scale }
     }
 
-    pub fn rotate(&self, vector: &Vec3) -> Vec3 {
-        Vec3::new(self.cos_theta * vector.x() - self.sin_theta * vector.z(),
-                  vector.y(),
-                  self.sin_theta * vector.x() + self.cos_theta * vector.z())
+    /// Find the matrix that scales, then rotates, then translates
+    pub fn matrix(&self) -> Mat4 {
+        Mat4::from_scale_rotation_translation(self.scale, self.rotation, self.translation)
     }
 
-    pub fn rotate_inv(&self, vector: &Vec3) -> Vec3 {
-        Vec3::new(self.cos_theta * vector.x() + self.sin_theta * vector.z(),
-                  vector.y(),
-                  -self.sin_theta * vector.x() + self.cos_theta * vector.z())
+    /// Interpolate toward another keyframe at the given time
+    ///
+    /// Translation and scale are interpolated linearly and rotation
+    /// spherically.
+    fn interpolate(&self, other: &Keyframe, time: f32) -> Keyframe {
+        let duration = other.time - self.time;
+        let t = if duration > 0.0 { (time - self.time) / duration } else { 0.0 };
+
+        Keyframe { time,
+                   translation: self.translation + t * (other.translation - self.translation),
+                   rotation: slerp(self.rotation, other.rotation, t),
+                   scale: self.scale + t * (other.scale - self.scale) }
     }
 }
 
-impl Hitable for Rotate {
-    fn hit(&self, ray: &Ray, t0: f32, t1: f32) -> Option<HitRecord> {
-        let origin = self.rotate(&ray.origin);
-        let direction = self.rotate(&ray.direction);
+/// AnimatedTransform moves an object through a sequence of keyframes
+///
+/// Times before the first keyframe and after the last keyframe hold the
+/// first and last keyframes respectively.
+#[derive(Clone)]
+pub struct AnimatedTransform {
+    keyframes: Vec<Keyframe>,
+}
 
-        let rotated_ray = Ray::new(origin, direction, ray.time);
+impl AnimatedTransform {
+    pub fn new(mut keyframes: Vec<Keyframe>) -> AnimatedTransform {
+        assert!(!keyframes.is_empty(), "an animated transform needs at least one keyframe");
+        keyframes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+        AnimatedTransform { keyframes }
+    }
 
-        if let Some(mut hit) = self.hitable.hit(&rotated_ray, t0, t1) {
-            hit.point = self.rotate_inv(&hit.point);
-            hit.shading_normal = self.rotate_inv(&hit.shading_normal);
-            Some(hit)
+    /// Find the interpolated keyframe at the given time
+    pub fn at(&self, time: f32) -> Keyframe {
+        let next = self.keyframes.partition_point(|keyframe| keyframe.time <= time);
+
+        if next == 0 {
+            self.keyframes[0]
+        } else if next == self.keyframes.len() {
+            self.keyframes[next - 1]
         } else {
-            None
+            self.keyframes[next - 1].interpolate(&self.keyframes[next], time)
         }
     }
 
-    fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
-        if let Some(mut bbox) = self.hitable.bounding_box(t0, t1) {
-            let mut minimum = Vec3::splat(f32::MAX);
-            let mut maximum = Vec3::splat(f32::MIN);
-            (0..2).for_each(|i| {
-                      (0..2).for_each(|j| {
-                                (0..2).for_each(|k| {
-                                          let x = i as f32 * bbox.maximum.x()
-                                                  + (1 - i) as f32 * bbox.minimum.x();
-                                          let y = j as f32 * bbox.maximum.y()
-                                                  + (1 - j) as f32 * bbox.minimum.y();
-                                          let z = k as f32 * bbox.maximum.z()
-                                                  + (1 - k) as f32 * bbox.minimum.z();
-                                          let newx = self.cos_theta * x + self.sin_theta * z;
-                                          let newz = -self.sin_theta * x + self.cos_theta * z;
-                                          let rotation = Vec3::new(newx, y, newz);
-                                          maximum = maximum.max(rotation);
-                                          minimum = minimum.min(rotation);
-                                      });
-                            });
-                  });
-
-            bbox.minimum = minimum;
-            bbox.maximum = maximum;
-            Some(bbox)
-        } else {
-            None
+    pub fn matrix(&self, time: f32) -> Mat4 {
+        self.at(time).matrix()
+    }
+
+    /// Find a box bounding the object space box over the time interval
+    ///
+    /// The motion between consecutive keyframes is sampled and the samples
+    /// are padded by how far the rotating corners can bulge past the chords
+    /// between them, so the result is conservative for any time in the
+    /// interval.
+    pub fn bounding_box(&self, bbox: &AABB, t0: f32, t1: f32) -> AABB {
+        let mut times = vec![t0, t1];
+        times.extend(self.keyframes
+                         .iter()
+                         .map(|keyframe| keyframe.time)
+                         .filter(|&time| time > t0 && time < t1));
+        times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let radius = bbox.minimum.abs().max(bbox.maximum.abs()).length();
+        let mut bounds = AABB::new();
+        for interval in times.windows(2) {
+            let (start, end) = (self.at(interval[0]), self.at(interval[1]));
+            let angle = 2.0 * start.rotation.dot(end.rotation).abs().min(1.0).acos();
+            let samples = if angle > 1e-4 { MOTION_BOUND_SAMPLES } else { 1 };
+            let step = angle / samples as f32;
+
+            let scale = start.scale.abs().max(end.scale.abs()).max_element();
+            let scale_change = (end.scale - start.scale).abs().max_element() / samples as f32;
+            let padding = radius * (scale * (1.0 - (0.5 * step).cos()) + scale_change * step);
+
+            for i in 0..=samples {
+                let time = interval[0] + (interval[1] - interval[0]) * i as f32 / samples as f32;
+                let sample = Affine::new(self.matrix(time)).bounding_box(bbox);
+                bounds = bounds.surrounding_box(&sample);
+            }
+            bounds.minimum -= Vec3::splat(padding);
+            bounds.maximum += Vec3::splat(padding);
+        }
+
+        if times.len() < 2 {
+            bounds = Affine::new(self.matrix(t0)).bounding_box(bbox);
         }
+        bounds
     }
 }
 
-pub struct Scale {
-    scalar: f32,
-    hitable: Arc<dyn Hitable>,
-}
+/// Spherically interpolate between two rotations along the shorter arc
+fn slerp(start: Quat, end: Quat, t: f32) -> Quat {
+    let start = Vec4::from(start);
+    let mut end = Vec4::from(end);
+    let mut cos_theta = start.dot(end);
+    if cos_theta < 0.0 {
+        end = -end;
+        cos_theta = -cos_theta;
+    }
 
-impl Scale {
-    pub fn new<H: Hitable + 'static>(scalar: f32, hitable: H) -> Scale {
-        let hitable = Arc::new(hitable);
-        Scale { scalar, hitable }
+    // nearly parallel rotations are interpolated linearly to avoid dividing by zero
+    if cos_theta > 0.9995 {
+        return Quat::from((start + t * (end - start)).normalize());
     }
+
+    let theta = cos_theta.acos();
+    let rotation = ((1.0 - t) * theta).sin() * start + (t * theta).sin() * end;
+    Quat::from(rotation / theta.sin())
 }
 
-impl Hitable for Scale {
-    /// Reference: http://woo4.me/raytracer/translations/
-    fn hit(&self, ray: &Ray, t0: f32, t1: f32) -> Option<HitRecord> {
-        let origin = ray.origin / self.scalar;
-        let direction = (ray.direction / self.scalar).normalize();
+/// An affine transformation from object space to world space
+///
+/// The inverse maps world space rays into object space and the inverse
+/// transpose maps object space normals back to world space.
+#[derive(Clone)]
+pub struct Affine {
+    pub matrix: Mat4,
+    pub inverse: Mat4,
+    normal_matrix: Mat4,
+}
 
-        let scaled_ray = Ray::new(origin, direction, ray.time);
+impl Affine {
+    pub fn new(matrix: Mat4) -> Affine {
+        let inverse = matrix.inverse();
+        Affine { matrix,
+                 inverse,
+                 normal_matrix: inverse.transpose() }
+    }
 
-        if let Some(mut hit) = self.hitable.hit(&scaled_ray, t0, t1) {
-            hit.point = hit.point * self.scalar;
-            hit.shading_normal = (hit.shading_normal / self.scalar).normalize();
-            Some(hit)
-        } else {
-            None
+    /// Map a world space ray into object space
+    ///
+    /// The object space direction is normalized, so distances along the
+    /// object space ray are the world space distances multiplied by the
+    /// returned scale.
+    pub fn ray_to_object(&self, ray: &Ray) -> (Ray, f32) {
+        let origin = self.inverse.transform_point3(ray.origin);
+        let direction = self.inverse.transform_vector3(ray.direction);
+        let scale = direction.length();
+
+        let mut object_ray = Ray::new(origin, direction, ray.time);
+        object_ray.wavelength = ray.wavelength;
+        (object_ray, scale)
+    }
+
+    /// Map an object space hit back to world space
+    pub fn hit_to_world(&self, mut hit: HitRecord, scale: f32) -> HitRecord {
+        hit.parameter /= scale;
+        hit.point = self.matrix.transform_point3(hit.point);
+        hit.geometric_normal = self.normal_matrix.transform_vector3(hit.geometric_normal)
+                                                 .normalize();
+        hit.shading_normal = self.normal_matrix.transform_vector3(hit.shading_normal)
+                                               .normalize();
+        hit.dpdu = self.matrix.transform_vector3(hit.dpdu);
+        hit.dpdv = self.matrix.transform_vector3(hit.dpdv);
+        hit
+    }
+
+    /// Find the world space box bounding an object space box
+    ///
+    /// Every corner of the box is transformed, so the result is the
+    /// tightest axis aligned box around the transformed box.
+    pub fn bounding_box(&self, bbox: &AABB) -> AABB {
+        let mut transformed = AABB::new();
+        for corner in 0..8 {
+            let pick = |bit: usize, axis: usize| {
+                if corner & bit == 0 {
+                    bbox.minimum.as_ref()[axis]
+                } else {
+                    bbox.maximum.as_ref()[axis]
+                }
+            };
+            let point = self.matrix
+                            .transform_point3(Vec3::new(pick(1, 0), pick(2, 1), pick(4, 2)));
+            transformed = transformed.surrounding_box(&AABB::from(point, point));
         }
+        transformed
     }
+}
 
-    fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
-        if let Some(mut bbox) = self.hitable.bounding_box(t0, t1) {
-            bbox.minimum *= self.scalar;
-            bbox.maximum *= self.scalar;
-            Some(bbox)
-        } else {
-            None
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use materials::Empty;
+    use sphere::Sphere;
+
+    #[test]
+    fn test_transform_normals_and_bounds() {
+        let ellipsoid = Transform::scale(Vec3::new(2.0, 1.0, 1.0),
+                                         Sphere::new(Vec3::zero(),
+                                                     Vec3::zero(),
+                                                     1.0,
+                                                     Empty::new(),
+                                                     0.0,
+                                                     1.0));
+        let ray = Ray::new(Vec3::new(1.0, 0.5, 10.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let hit = ellipsoid.hit(&ray, 1e-4, f32::MAX).unwrap();
+        let expected = Vec3::new(hit.point.x() / 4.0, hit.point.y(), hit.point.z()).normalize();
+        assert!((hit.point.z() - 10.0 + hit.parameter).abs() < 1e-4);
+        assert!((hit.geometric_normal - expected).length() < 1e-4);
+
+        let rotated = ellipsoid.then(Mat4::from_rotation_z(f32::consts::FRAC_PI_2));
+        let bbox = rotated.bounding_box(0.0, 1.0).unwrap();
+        assert!((bbox.maximum - Vec3::new(1.0, 2.0, 1.0)).length() < 1e-4);
+        assert!((bbox.minimum + Vec3::new(1.0, 2.0, 1.0)).length() < 1e-4);
+    }
+
+    #[test]
+    fn test_animated_bounds_cover_motion() {
+        let start = Keyframe::new(0.0, Vec3::zero(), Quat::identity(), Vec3::one());
+        let end = Keyframe::new(1.0,
+                                Vec3::new(3.0, 0.0, 0.0),
+                                Quat::from_rotation_y(3.0),
+                                Vec3::new(1.0, 2.0, 1.0));
+        let animation = AnimatedTransform::new(vec![start, end]);
+        let middle = animation.at(0.5);
+        assert!((middle.rotation.dot(Quat::from_rotation_y(1.5)) - 1.0).abs() < 1e-4);
+
+        let cube = AABB::from(Vec3::new(-1.0, -1.0, -0.5), Vec3::new(2.0, 1.0, 0.5));
+        let bbox = animation.bounding_box(&cube, 0.0, 1.0);
+        for i in 0..=1000 {
+            let matrix = animation.matrix(i as f32 / 1000.0);
+            for corner in 0..8 {
+                let point = Vec3::new(if corner & 1 == 0 { -1.0 } else { 2.0 },
+                                      if corner & 2 == 0 { -1.0 } else { 1.0 },
+                                      if corner & 4 == 0 { -0.5 } else { 0.5 });
+                let point = matrix.transform_point3(point);
+                assert!(point.cmpge(bbox.minimum).all() && point.cmple(bbox.maximum).all());
+            }
         }
     }
 }
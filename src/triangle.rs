@@ -2,14 +2,16 @@ use std::f32;
 use std::path::Path;
 use std::sync::Arc;
 
-use glam::Vec3;
+use glam::{Vec2, Vec3};
 use tobj;
 
 use aabb::AABB;
+use basis::OrthonormalBasis;
+use bvh::BVH;
 use hitable::{HitRecord, Hitable};
 use materials::Material;
 use ray::Ray;
-use world::World;
+use utils;
 
 #[derive(Clone)]
 pub struct Triangle {
@@ -19,13 +21,67 @@ pub struct Triangle {
     n0: Vec3,
     n1: Vec3,
     n2: Vec3,
+    t0: Vec2,
+    t1: Vec2,
+    t2: Vec2,
     material: Arc<dyn Material>,
+    motion: Option<(Arc<VertexMotion>, usize)>,
 }
 
+/// The positions and normals of the vertices of a mesh at evenly spaced times
+///
+/// Each key holds the vertices of every triangle of the mesh, which share
+/// the keys and find their own vertices by index. The first key is at
+/// start_time and the last key at end_time. Rays at times in between see
+/// the vertices interpolated linearly between the two nearest keys, which
+/// blurs deforming meshes.
+pub struct VertexMotion {
+    pub start_time: f32,
+    pub end_time: f32,
+    pub positions: Vec<Vec<[Vec3; 3]>>,
+    pub normals: Vec<Vec<[Vec3; 3]>>,
+}
+
+impl VertexMotion {
+    pub fn new(start_time: f32,
+               end_time: f32,
+               positions: Vec<Vec<[Vec3; 3]>>,
+               normals: Vec<Vec<[Vec3; 3]>>)
+               -> VertexMotion {
+        assert!(positions.len() >= 2, "vertex motion needs at least two keys");
+        assert_eq!(positions.len(), normals.len());
+        assert!(positions.iter().chain(normals.iter()).all(|key| key.len() == positions[0].len()),
+                "every key of vertex motion needs the same triangles");
+        VertexMotion { start_time,
+                       end_time,
+                       positions,
+                       normals }
+    }
+
+    /// Find the interpolated positions and normals of a triangle at the given time
+    fn at(&self, index: usize, time: f32) -> ([Vec3; 3], [Vec3; 3]) {
+        let duration = self.end_time - self.start_time;
+        let t = if duration > 0.0 { (time - self.start_time) / duration } else { 0.0 };
+        let position = utils::clamp(t, 0.0, 1.0) * (self.positions.len() - 1) as f32;
+        let key = (position as usize).min(self.positions.len() - 2);
+        let fraction = position - key as f32;
+
+        let lerp = |keys: &Vec<Vec<[Vec3; 3]>>| {
+            let (a, b) = (keys[key][index], keys[key + 1][index]);
+            [a[0] + fraction * (b[0] - a[0]),
+             a[1] + fraction * (b[1] - a[1]),
+             a[2] + fraction * (b[2] - a[2])]
+        };
+        (lerp(&self.positions), lerp(&self.normals))
+    }
+}
+
+/// A mesh of triangles with its own bounding volume hierarchy
+///
+/// The mesh is a single object in the scene: the scene BVH finds the mesh
+/// and the mesh BVH finds the triangles within it.
 pub struct TriangleMesh {
-    triangles: Vec<Triangle>,
-    hitables: World,
-    material: Arc<dyn Material>,
+    bvh: BVH,
 }
 
 impl Triangle {
@@ -45,7 +101,11 @@ impl Triangle {
                    n0: n0,
                    n1: n1,
                    n2: n2,
-                   material: material }
+                   t0: Vec2::new(0.0, 0.0),
+                   t1: Vec2::new(1.0, 0.0),
+                   t2: Vec2::new(0.0, 1.0),
+                   material: material,
+                   motion: None }
     }
 
     pub fn from_box(v0: Vec3,
@@ -62,15 +122,94 @@ impl Triangle {
                    n0: n0,
                    n1: n1,
                    n2: n2,
-                   material: material }
+                   t0: Vec2::new(0.0, 0.0),
+                   t1: Vec2::new(1.0, 0.0),
+                   t2: Vec2::new(0.0, 1.0),
+                   material: material,
+                   motion: None }
+    }
+
+    /// Assign texture coordinates to the vertices of the triangle
+    ///
+    /// Triangles without texture coordinates use their barycentric coordinates.
+    pub fn with_texcoords(mut self, t0: Vec2, t1: Vec2, t2: Vec2) -> Triangle {
+        self.t0 = t0;
+        self.t1 = t1;
+        self.t2 = t2;
+        self
     }
 
+    /// Move the vertices of the triangle over the shutter interval
+    ///
+    /// The triangle takes the vertices at index in each key of the motion.
+    /// The vertices and normals given at construction are replaced by the
+    /// first key.
+    pub fn with_motion(mut self, motion: Arc<VertexMotion>, index: usize) -> Triangle {
+        let (positions, normals) = (motion.positions[0][index], motion.normals[0][index]);
+        self.v0 = positions[0];
+        self.v1 = positions[1];
+        self.v2 = positions[2];
+        self.n0 = normals[0];
+        self.n1 = normals[1];
+        self.n2 = normals[2];
+        self.motion = Some((motion, index));
+        self
+    }
+
+    /// Find the positions and normals of the vertices at the given time
+    fn vertices(&self, time: f32) -> ([Vec3; 3], [Vec3; 3]) {
+        match &self.motion {
+            Some((motion, index)) => motion.at(*index, time),
+            None => ([self.v0, self.v1, self.v2], [self.n0, self.n1, self.n2]),
+        }
+    }
+
+    /// Compute the partial derivatives of the surface with respect to u and v
+    ///
+    /// The derivatives are found by solving the linear system relating the
+    /// triangle's edges to the differences of its texture coordinates. Degenerate
+    /// texture coordinates fall back to an arbitrary frame around the normal.
+    ///
+    /// See Section 3.6.2 in Physically Based Rendering, Third Edition.
+    fn derivatives(&self, vertices: &[Vec3; 3], normal: Vec3) -> (Vec3, Vec3) {
+        let duv02 = self.t0 - self.t2;
+        let duv12 = self.t1 - self.t2;
+        let dp02 = vertices[0] - vertices[2];
+        let dp12 = vertices[1] - vertices[2];
+
+        let determinant = duv02.x() * duv12.y() - duv02.y() * duv12.x();
+
+        if determinant.abs() < 1e-8 {
+            let frame = OrthonormalBasis::new(&normal);
+            (frame.u(), frame.v())
+        } else {
+            let inverse_determinant = 1.0 / determinant;
+            let dpdu = (duv12.y() * dp02 - duv02.y() * dp12) * inverse_determinant;
+            let dpdv = (duv02.x() * dp12 - duv12.x() * dp02) * inverse_determinant;
+            (dpdu, dpdv)
+        }
+    }
+
+    /// Find the minimum of the vertices over every key of the motion
     pub fn minimum(&self) -> Vec3 {
-        self.v0.min(self.v1.min(self.v2))
+        match &self.motion {
+            Some((motion, index)) => {
+                let keys = motion.positions.iter().map(|key| key[*index]);
+                keys.fold(Vec3::splat(f32::MAX), |minimum, v| minimum.min(v[0].min(v[1].min(v[2]))))
+            }
+            None => self.v0.min(self.v1.min(self.v2)),
+        }
     }
 
+    /// Find the maximum of the vertices over every key of the motion
     pub fn maximum(&self) -> Vec3 {
-        self.v0.max(self.v1.max(self.v2))
+        match &self.motion {
+            Some((motion, index)) => {
+                let keys = motion.positions.iter().map(|key| key[*index]);
+                keys.fold(Vec3::splat(f32::MIN), |maximum, v| maximum.max(v[0].max(v[1].max(v[2]))))
+            }
+            None => self.v0.max(self.v1.max(self.v2)),
+        }
     }
 }
 
@@ -83,9 +222,10 @@ impl Hitable for Triangle {
     /// Journal of Graphics Tools Vol. 2 Issue 1, 1997
     /// http://www.acm.org/jgt/papers/MollerTrumbore97/
     ///
-    fn hit(&self, ray: &Ray, position_min: f32, _position_max: f32) -> Option<HitRecord> {
-        let edge1 = self.v1 - self.v0;
-        let edge2 = self.v2 - self.v0;
+    fn hit(&self, ray: &Ray, position_min: f32, position_max: f32) -> Option<HitRecord> {
+        let ([v0, v1, v2], [n0, n1, n2]) = self.vertices(ray.time);
+        let edge1 = v1 - v0;
+        let edge2 = v2 - v0;
 
         let pvec = ray.direction.cross(edge2);
         let determinant = edge1.dot(pvec);
@@ -94,7 +234,7 @@ impl Hitable for Triangle {
             return None;
         }
 
-        let tvec = ray.origin - self.v0;
+        let tvec = ray.origin - v0;
         let mut u = tvec.dot(pvec);
 
         if u < 0.0 || u > determinant {
@@ -112,92 +252,186 @@ impl Hitable for Triangle {
 
         let inverse_determinant = 1.0 / determinant;
         t *= inverse_determinant;
+        if t < position_min || t > position_max {
+            return None;
+        }
+
         u *= inverse_determinant;
         v *= inverse_determinant;
 
-        let point = u * self.v0 + v * self.v1 + (1.0 - u - v) * self.v2;
+        let w = 1.0 - u - v;
+        let point = w * v0 + u * v1 + v * v2;
         let geometric_normal = edge1.cross(edge2).normalize();
-        let shading_normal = ((1.0 - u - v) * self.n0 + u * self.n1 + v * self.n2).normalize();
-
-        Some(HitRecord::new(t,
-                            u,
-                            v,
-                            point,
-                            geometric_normal,
-                            shading_normal,
-                            self.material.clone()))
+        let shading_normal = (w * n0 + u * n1 + v * n2).normalize();
+        let texcoord = w * self.t0 + u * self.t1 + v * self.t2;
+        let (dpdu, dpdv) = self.derivatives(&[v0, v1, v2], geometric_normal);
+
+        let record = HitRecord::new(t,
+                                    texcoord.x(),
+                                    texcoord.y(),
+                                    point,
+                                    geometric_normal,
+                                    shading_normal,
+                                    self.material.clone()).with_derivatives(dpdu, dpdv);
+
+        if record.is_opaque(ray) {
+            Some(record)
+        } else {
+            None
+        }
     }
 
     /// Create a bounding box around the triangle
     ///
     /// The bounding box is created using the minimum
-    /// and maximum points of all of the vertices
+    /// and maximum points of all of the vertices. Moving triangles
+    /// are bounded over every key of their motion.
     fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
         Some(AABB::from(self.minimum(), self.maximum()))
     }
 }
 
 impl TriangleMesh {
-    pub fn new(triangles: Vec<Triangle>, material: Arc<dyn Material>) -> TriangleMesh {
-        let mut world = World::new();
-
-        for triangle in &triangles {
-            world.add(triangle.clone());
-        }
+    pub fn new(triangles: Vec<Triangle>) -> TriangleMesh {
+        let mut hitables: Vec<Arc<dyn Hitable>> =
+            triangles.into_iter()
+                     .map(|triangle| Arc::new(triangle) as Arc<dyn Hitable>)
+                     .collect();
 
-        TriangleMesh { triangles: triangles,
-                       hitables: world,
-                       material: material }
+        TriangleMesh { bvh: BVH::new(&mut hitables, 0.0, 1.0) }
     }
 
     pub fn from(filepath: &str, material: Arc<dyn Material>) -> TriangleMesh {
-        let obj = tobj::load_obj(&Path::new(&filepath));
-        let (models, _) = obj.unwrap();
-
-        let mut triangles: Vec<Triangle> = Vec::new();
-        for model in models {
-            let mesh = &model.mesh;
-
-            let positions: Vec<Vec3> = mesh.positions
-                                           .chunks(3)
-                                           .map(|i| Vec3::new(i[0], i[1], i[2]))
-                                           .collect();
-
-            let normals: Vec<Vec3> = mesh.normals
-                                         .chunks(3)
-                                         .map(|i| Vec3::new(i[0], i[1], i[2]))
-                                         .collect();
-
-            for i in 0..mesh.indices.len() / 3 {
-                let (i, j, k) =
-                    (mesh.indices[3 * i], mesh.indices[3 * i + 1], mesh.indices[3 * i + 2]);
-                let (v0, v1, v2) =
-                    (positions[i as usize], positions[j as usize], positions[k as usize]);
-                let (n0, n1, n2) = (normals[i as usize], normals[j as usize], normals[k as usize]);
-
-                let triangle = Triangle::from_box(v0, v1, v2, n0, n1, n2, material.clone());
-                triangles.push(triangle);
-            }
-        }
+        TriangleMesh::new(load_triangles(filepath, &material))
+    }
 
-        TriangleMesh::new(triangles, material)
+    /// Load a deforming mesh from OBJ files of the same mesh at evenly spaced times
+    ///
+    /// The first file holds the mesh at start_time and the last at end_time.
+    /// Every file must list the same faces in the same order; only the
+    /// positions and normals are taken from all but the first file.
+    pub fn from_frames(filepaths: &[&str],
+                       start_time: f32,
+                       end_time: f32,
+                       material: Arc<dyn Material>)
+                       -> TriangleMesh {
+        assert!(filepaths.len() >= 2, "a deforming mesh needs at least two frames");
+        let mut frames: Vec<Vec<Triangle>> =
+            filepaths.iter()
+                     .map(|filepath| load_triangles(filepath, &material))
+                     .collect();
+        assert!(frames.iter().all(|frame| frame.len() == frames[0].len()),
+                "every frame of a deforming mesh needs the same faces");
+
+        let positions = frames.iter()
+                              .map(|frame| frame.iter().map(|t| [t.v0, t.v1, t.v2]).collect())
+                              .collect();
+        let normals = frames.iter()
+                            .map(|frame| frame.iter().map(|t| [t.n0, t.n1, t.n2]).collect())
+                            .collect();
+        let motion = Arc::new(VertexMotion::new(start_time, end_time, positions, normals));
+
+        let triangles = frames.swap_remove(0)
+                              .into_iter()
+                              .enumerate()
+                              .map(|(i, triangle)| triangle.with_motion(motion.clone(), i))
+                              .collect();
+        TriangleMesh::new(triangles)
     }
 }
 
 impl Hitable for TriangleMesh {
     fn hit(&self, ray: &Ray, position_min: f32, position_max: f32) -> Option<HitRecord> {
-        self.hitables.hit(&ray, position_min, position_max)
+        self.bvh.hit(&ray, position_min, position_max)
     }
 
-    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
-        let mut minimum = Vec3::splat(f32::MAX);
-        let mut maximum = Vec3::splat(f32::MIN);
+    /// The root of the mesh's BVH bounds every triangle over all of its keys
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
+        self.bvh.bounding_box(t0, t1)
+    }
+}
+
+/// Load the triangles of every model in an OBJ file
+fn load_triangles(filepath: &str, material: &Arc<dyn Material>) -> Vec<Triangle> {
+    let obj = tobj::load_obj(&Path::new(&filepath));
+    let (models, _) = obj.unwrap();
+
+    let mut triangles: Vec<Triangle> = Vec::new();
+    for model in models {
+        let mesh = &model.mesh;
+
+        let positions: Vec<Vec3> = mesh.positions
+                                       .chunks(3)
+                                       .map(|i| Vec3::new(i[0], i[1], i[2]))
+                                       .collect();
+
+        let normals: Vec<Vec3> = mesh.normals
+                                     .chunks(3)
+                                     .map(|i| Vec3::new(i[0], i[1], i[2]))
+                                     .collect();
+
+        let texcoords: Vec<Vec2> = mesh.texcoords
+                                       .chunks(2)
+                                       .map(|i| Vec2::new(i[0], i[1]))
+                                       .collect();
+
+        for i in 0..mesh.indices.len() / 3 {
+            let (i, j, k) =
+                (mesh.indices[3 * i], mesh.indices[3 * i + 1], mesh.indices[3 * i + 2]);
+            let (v0, v1, v2) =
+                (positions[i as usize], positions[j as usize], positions[k as usize]);
+            let (n0, n1, n2) = (normals[i as usize], normals[j as usize], normals[k as usize]);
+
+            let mut triangle = Triangle::from_box(v0, v1, v2, n0, n1, n2, material.clone());
+            if !texcoords.is_empty() {
+                triangle = triangle.with_texcoords(texcoords[i as usize],
+                                                   texcoords[j as usize],
+                                                   texcoords[k as usize]);
+            }
+            triangles.push(triangle);
+        }
+    }
+
+    triangles
+}
 
-        for triangle in &self.triangles {
-            minimum = minimum.min(triangle.minimum());
-            maximum = maximum.max(triangle.maximum());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use materials::Empty;
+
+    #[test]
+    fn test_moving_triangle_follows_ray_time() {
+        let normal = Vec3::new(0.0, 0.0, 1.0);
+        let key = |z: f32| {
+            [Vec3::new(-1.0, -1.0, z), Vec3::new(1.0, -1.0, z), Vec3::new(0.0, 1.0, z)]
+        };
+        let motion = VertexMotion::new(0.0,
+                                       1.0,
+                                       vec![vec![key(0.0)], vec![key(-1.0)], vec![key(-3.0)]],
+                                       vec![vec![[normal; 3]]; 3]);
+        let triangle = Triangle::new(Vec3::zero(),
+                                     Vec3::zero(),
+                                     Vec3::zero(),
+                                     normal,
+                                     normal,
+                                     normal,
+                                     Empty::new()).with_motion(Arc::new(motion), 0);
+
+        for &(time, z) in &[(0.0, 0.0), (0.25, -0.5), (0.75, -2.0), (1.0, -3.0)] {
+            let ray = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0), time);
+            let hit = triangle.hit(&ray, 1e-4, f32::MAX).unwrap();
+            assert!((hit.point.z() - z).abs() < 1e-4);
         }
 
-        Some(AABB::from(minimum, maximum))
+        let bbox = triangle.bounding_box(0.0, 1.0).unwrap();
+        assert_eq!(bbox.minimum.z(), -3.0);
+        assert_eq!(bbox.maximum.z(), 0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least two frames")]
+    fn test_deforming_mesh_needs_frames() {
+        TriangleMesh::from_frames(&[], 0.0, 1.0, Arc::new(Empty::new()));
     }
 }
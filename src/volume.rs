@@ -1,64 +1,47 @@
-use std::f32;
 use std::sync::Arc;
 
 use glam::Vec3;
 
 use aabb::AABB;
+use grid::VoxelGrid;
 use hitable::{HitRecord, Hitable};
-use materials::{Isotropic, Material};
+use materials::{Empty, Interior, Material, Transparent};
+use medium::{Density, Medium};
 use ray::Ray;
-use texture::Texture;
+use rectangle::Rectangle;
 
+/// Volume fills a closed boundary with a participating medium
+///
+/// The boundary itself is invisible: rays crossing it are passed through
+/// unchanged and the integrator tracks them through the medium while they
+/// are inside. The boundary must be closed with normals pointing outward.
 pub struct Volume {
-    density: f32,
     boundary: Arc<dyn Hitable>,
     material: Arc<dyn Material>,
 }
 
 impl Volume {
-    pub fn new<H: Hitable + 'static, T: Texture + 'static>(density: f32,
-                                                           boundary: H,
-                                                           texture: T)
-                                                           -> Volume {
+    pub fn new<H: Hitable + 'static>(boundary: H, medium: Medium) -> Volume {
         let boundary = Arc::new(boundary);
-        let material = Arc::new(Isotropic::new(texture));
-        Volume { density,
-                 boundary,
-                 material }
+        let material = Arc::new(Interior::new(Transparent::new(), medium));
+        Volume { boundary, material }
+    }
+
+    /// Fill the world space bounds of a voxel grid with a medium whose
+    /// density is read from the grid
+    pub fn from_grid(grid: VoxelGrid, sigma_a: Vec3, sigma_s: Vec3, g: f32) -> Volume {
+        let bounds = grid.world_bounds();
+        let boundary = Rectangle::new(bounds.minimum, bounds.maximum, Arc::new(Empty::new()));
+        let medium = Medium::new(sigma_a, sigma_s, g, Density::Grid(Arc::new(grid)));
+        Volume::new(boundary, medium)
     }
 }
 
 impl Hitable for Volume {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        if let Some(mut hit1) = self.boundary.hit(&ray, f32::MIN, f32::MAX) {
-            if let Some(mut hit2) = self.boundary.hit(&ray, hit1.parameter + 0.0001, f32::MAX) {
-                if hit1.parameter < t_min {
-                    hit1.parameter = t_min
-                };
-                if hit2.parameter > t_max {
-                    hit2.parameter = t_max
-                };
-                if hit1.parameter < hit2.parameter {
-                    let distance_inside_boundary =
-                        (hit2.parameter - hit1.parameter) * ray.direction.length();
-                    let hit_distance = -(1.0 / self.density) * rand::random::<f32>().ln();
-
-                    if hit_distance < distance_inside_boundary {
-                        let t = hit1.parameter + hit_distance / ray.direction.length();
-                        let point = ray.point_at_parameter(t);
-                        let normal = Vec3::new(1.0, 0.0, 0.0);
-                        return Some(HitRecord::new(t,
-                                                   0.0,
-                                                   0.0,
-                                                   point,
-                                                   normal,
-                                                   normal,
-                                                   self.material.clone()));
-                    }
-                }
-            }
-        }
-        None
+        let mut hit = self.boundary.hit(ray, t_min, t_max)?;
+        hit.material = self.material.clone();
+        Some(hit)
     }
 
     fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {